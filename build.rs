@@ -6,5 +6,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // generate fabric-rpc header
     prost_build::compile_protos(&["proto/fabricrpc.proto"], &["proto/"])?;
 
+    // generate built-in service messages, and their descriptors for reflection
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    prost_build::Config::new()
+        .file_descriptor_set_path(out_dir.join("builtin_descriptor.bin"))
+        .compile_protos(
+            &["proto/reflection.proto", "proto/health.proto"],
+            &["proto/"],
+        )?;
+
     Ok(())
 }
@@ -1,37 +1,79 @@
+use std::collections::HashSet;
+
 use convert_case::{Case, Casing};
 use proc_macro2::TokenStream;
 use prost_build::Method;
 use quote::{format_ident, quote};
 
+use crate::code_gen::message_type;
+
 // client code
-pub fn generate_internal(service: &prost_build::Service) -> TokenStream {
+pub fn generate_internal(
+    service: &prost_build::Service,
+    tonic_compat: bool,
+    build_mock: bool,
+    one_way: &HashSet<String>,
+    codec: &TokenStream,
+) -> TokenStream {
     let service_ident = quote::format_ident!("{}Client", service.name);
-    let client_mod = quote::format_ident!("{}_client", service.name.to_case(Case::Snake));
+    // tonic owns the <service>_client module name in compat mode
+    let client_mod = if tonic_compat {
+        quote::format_ident!("{}_fabric_client", service.name.to_case(Case::Snake))
+    } else {
+        quote::format_ident!("{}_client", service.name.to_case(Case::Snake))
+    };
 
-    let methods = generate_methods(service);
+    let methods = generate_methods(service, one_way, codec);
+    let mock_code = if build_mock {
+        generate_mock(service, one_way)
+    } else {
+        TokenStream::new()
+    };
     // println!("{}",methods);
     quote! {
         pub mod #client_mod {
-            use fabric_rpc_rs::client::Client2;
+            use fabric_rpc_rs::client::{
+                one_way_with_codec, unary_with_codec, Channel, Client2, InterceptedChannel, Interceptor,
+            };
             use windows::core::{Error, HSTRING};
 
-            pub struct #service_ident{
-                c: Client2
+            // client generic over the channel, defaults to fabric transport client
+            pub struct #service_ident<C = Client2> {
+                c: C
             }
 
-            impl #service_ident {
-                pub async fn connect(addr: HSTRING) -> Result<#service_ident, Error> {
+            impl #service_ident<Client2> {
+                pub async fn connect(addr: HSTRING) -> Result<#service_ident<Client2>, Error> {
                     let c = Client2::connect(addr).await?;
                     Ok(#service_ident { c })
                 }
+            }
+
+            impl<C: Channel> #service_ident<C> {
+                pub fn new(channel: C) -> #service_ident<C> {
+                    #service_ident { c: channel }
+                }
+
+                pub fn with_interceptor<I: Interceptor>(
+                    channel: C,
+                    interceptor: I,
+                ) -> #service_ident<InterceptedChannel<C, I>> {
+                    #service_ident { c: InterceptedChannel::new(channel, interceptor) }
+                }
+
                 #methods
             }
 
+            #mock_code
         }
     }
 }
 
-pub fn generate_methods(service: &prost_build::Service) -> TokenStream {
+pub fn generate_methods(
+    service: &prost_build::Service,
+    one_way: &HashSet<String>,
+    codec: &TokenStream,
+) -> TokenStream {
     let mut stream = TokenStream::new();
 
     for method in &service.methods {
@@ -39,23 +81,174 @@ pub fn generate_methods(service: &prost_build::Service) -> TokenStream {
             // do not support streaming
             continue;
         }
-        stream.extend(generate_unary(service, method));
+        if one_way.contains(&method.name) {
+            stream.extend(generate_one_way(service, method, codec));
+        } else {
+            stream.extend(generate_unary(service, method, codec));
+        }
     }
     stream
 }
 
-fn generate_unary(service: &prost_build::Service, method: &Method) -> TokenStream {
+fn generate_unary(
+    service: &prost_build::Service,
+    method: &Method,
+    codec: &TokenStream,
+) -> TokenStream {
     let ident = format_ident!("{}", method.name);
-    let request_type = format_ident!("{}", method.input_type);
-    let response_type = format_ident!("{}", method.output_type);
+    let request_type = message_type(&method.input_type);
+    let response_type = message_type(&method.output_type);
     let url = format!("/{}.{}/{}", service.package, service.name, method.name);
     quote! {
         pub async fn #ident (&self,
             timoutmilliseconds: u32,
-            request: super::#request_type,
-        ) -> Result<super::#response_type, tonic::Status> {
+            request: #request_type,
+        ) -> Result<#response_type, tonic::Status> {
             let url = String::from(#url);
-            self.c.request(url, &request, timoutmilliseconds).await
+            unary_with_codec(&self.c, url, &request, timoutmilliseconds, &<#codec>::default()).await
+        }
+    }
+}
+
+// fire and forget, returns once the msg is handed to the transport
+fn generate_one_way(
+    service: &prost_build::Service,
+    method: &Method,
+    codec: &TokenStream,
+) -> TokenStream {
+    let ident = format_ident!("{}", method.name);
+    let request_type = message_type(&method.input_type);
+    let url = format!("/{}.{}/{}", service.package, service.name, method.name);
+    quote! {
+        pub async fn #ident (&self,
+            request: #request_type,
+        ) -> Result<(), tonic::Status> {
+            let url = String::from(#url);
+            one_way_with_codec(&self.c, url, &request, &<#codec>::default()).await
+        }
+    }
+}
+
+// client trait implemented by the real client and the mock client,
+// so app code can depend on the trait and be tested without transport.
+fn generate_mock(service: &prost_build::Service, one_way: &HashSet<String>) -> TokenStream {
+    let service_ident = quote::format_ident!("{}Client", service.name);
+    let api_ident = quote::format_ident!("{}ClientApi", service.name);
+    let mock_ident = quote::format_ident!("Mock{}Client", service.name);
+
+    let mut trait_methods = TokenStream::new();
+    let mut client_impls = TokenStream::new();
+    let mut mock_impls = TokenStream::new();
+    let mut mock_fields = TokenStream::new();
+    let mut mock_inits = TokenStream::new();
+    let mut mock_accessors = TokenStream::new();
+    for method in &service.methods {
+        if method.client_streaming || method.server_streaming {
+            // do not support streaming
+            continue;
+        }
+        let ident = format_ident!("{}", method.name);
+        let expect_ident = format_ident!("expect_{}", method.name);
+        let request_type = message_type(&method.input_type);
+        let method_name = format!("{}.{}/{}", service.package, service.name, method.name);
+
+        let response_type;
+        if one_way.contains(&method.name) {
+            response_type = quote!(());
+            trait_methods.extend(quote! {
+                async fn #ident(&self,
+                    request: #request_type,
+                ) -> Result<(), tonic::Status>;
+            });
+            client_impls.extend(quote! {
+                async fn #ident(&self,
+                    request: #request_type,
+                ) -> Result<(), tonic::Status> {
+                    Self::#ident(self, request).await
+                }
+            });
+            mock_impls.extend(quote! {
+                async fn #ident(&self,
+                    request: #request_type,
+                ) -> Result<(), tonic::Status> {
+                    self.#ident.call(request)
+                }
+            });
+        } else {
+            response_type = message_type(&method.output_type);
+            trait_methods.extend(quote! {
+                async fn #ident(&self,
+                    timoutmilliseconds: u32,
+                    request: #request_type,
+                ) -> Result<#response_type, tonic::Status>;
+            });
+            client_impls.extend(quote! {
+                async fn #ident(&self,
+                    timoutmilliseconds: u32,
+                    request: #request_type,
+                ) -> Result<#response_type, tonic::Status> {
+                    Self::#ident(self, timoutmilliseconds, request).await
+                }
+            });
+            mock_impls.extend(quote! {
+                async fn #ident(&self,
+                    _timoutmilliseconds: u32,
+                    request: #request_type,
+                ) -> Result<#response_type, tonic::Status> {
+                    self.#ident.call(request)
+                }
+            });
+        }
+        mock_fields.extend(quote! {
+            #ident: MockMethod<#request_type, #response_type>,
+        });
+        mock_inits.extend(quote! {
+            #ident: MockMethod::new(#method_name),
+        });
+        mock_accessors.extend(quote! {
+            pub fn #expect_ident(&self) -> &MockMethod<#request_type, #response_type> {
+                &self.#ident
+            }
+        });
+    }
+
+    quote! {
+        use fabric_rpc_rs::mock::MockMethod;
+
+        #[tonic::async_trait]
+        pub trait #api_ident: Send + Sync {
+            #trait_methods
+        }
+
+        #[tonic::async_trait]
+        impl<C: Channel> #api_ident for #service_ident<C> {
+            #client_impls
+        }
+
+        // mock client with per method expectations
+        pub struct #mock_ident {
+            #mock_fields
+        }
+
+        impl #mock_ident {
+            pub fn new() -> #mock_ident {
+                #mock_ident {
+                    #mock_inits
+                }
+            }
+
+            #mock_accessors
+        }
+
+        impl Default for #mock_ident {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        #[tonic::async_trait]
+        impl #api_ident for #mock_ident {
+            #mock_impls
         }
     }
 }
@@ -1,40 +1,117 @@
+use std::collections::HashSet;
+
 use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+
+use crate::{client, server, Builder};
 
-use crate::{client, server};
-//use prost_build::Service;
+const DEFAULT_CODEC_PATH: &str = "fabric_rpc_rs::codec::ProstCodec";
 
 pub struct ServiceGenerator {
-    //builder: Builder
+    builder: Builder,
+    // tonic generator used in tonic compat mode
+    tonic: Option<Box<dyn prost_build::ServiceGenerator>>,
 }
 
 impl ServiceGenerator {
-    pub fn new() -> Self {
-        ServiceGenerator {
-          // builder,
-          // clients: TokenStream::default(),
-          // servers: TokenStream::default(),
-      }
+    pub fn new(builder: Builder) -> Self {
+        let tonic = if builder.tonic_compat {
+            Some(tonic_build::configure().service_generator())
+        } else {
+            None
+        };
+        ServiceGenerator { builder, tonic }
     }
 }
 
 impl prost_build::ServiceGenerator for ServiceGenerator {
     fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
-        let builder = CodeGenBuilder {};
+        if let Some(tonic) = self.tonic.as_mut() {
+            tonic.generate(service.clone(), buf);
+        }
+        let builder = CodeGenBuilder {
+            tonic_compat: self.builder.tonic_compat,
+            build_mock: self.builder.build_mock,
+            one_way: one_way_methods(&service, &self.builder.one_way_methods),
+            codec: self
+                .builder
+                .codec_path
+                .as_deref()
+                .unwrap_or(DEFAULT_CODEC_PATH)
+                .parse()
+                .expect("codec path should be a rust type path"),
+        };
         let client_code = builder.generate_client(&service);
         buf.push_str(client_code.to_string().as_str());
         let server_code = builder.generate_server(&service);
         buf.push_str(server_code.to_string().as_str());
     }
+
+    fn finalize(&mut self, buf: &mut String) {
+        if let Some(tonic) = self.tonic.as_mut() {
+            tonic.finalize(buf);
+        }
+    }
+
+    fn finalize_package(&mut self, package: &str, buf: &mut String) {
+        if let Some(tonic) = self.tonic.as_mut() {
+            tonic.finalize_package(package, buf);
+        }
+    }
 }
 
-struct CodeGenBuilder {}
+struct CodeGenBuilder {
+    tonic_compat: bool,
+    build_mock: bool,
+    // rust names of the one-way methods of the service
+    one_way: HashSet<String>,
+    // codec type of the bodies
+    codec: TokenStream,
+}
 
 impl CodeGenBuilder {
     pub fn generate_client(&self, service: &prost_build::Service) -> TokenStream {
-        client::generate_internal(service)
+        client::generate_internal(
+            service,
+            self.tonic_compat,
+            self.build_mock,
+            &self.one_way,
+            &self.codec,
+        )
     }
 
     pub fn generate_server(&self, service: &prost_build::Service) -> TokenStream {
-        server::generate_internal(service)
+        if self.tonic_compat {
+            server::generate_tonic_router(service, &self.one_way, &self.codec)
+        } else {
+            server::generate_internal(service, &self.one_way, &self.codec)
+        }
+    }
+}
+
+// methods marked by the builder, returning Empty alone keeps a method unary
+fn one_way_methods(service: &prost_build::Service, marked: &[String]) -> HashSet<String> {
+    service
+        .methods
+        .iter()
+        .filter(|m| {
+            let path = format!(
+                "{}.{}.{}",
+                service.package, service.proto_name, m.proto_name
+            );
+            marked.contains(&path)
+        })
+        .map(|m| m.name.clone())
+        .collect()
+}
+
+// message type as seen from the generated module.
+// prost maps google.protobuf.Empty to ().
+pub(crate) fn message_type(name: &str) -> TokenStream {
+    if name == "()" {
+        quote!(())
+    } else {
+        let ident = format_ident!("{}", name);
+        quote!(super::#ident)
     }
 }
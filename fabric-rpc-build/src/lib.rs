@@ -1,4 +1,7 @@
-use std::{io, path::Path};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
 use code_gen::ServiceGenerator;
 use prost_build::Config;
@@ -8,13 +11,61 @@ mod code_gen;
 mod server;
 
 // code gen builder
-pub struct Builder {}
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    pub(crate) tonic_compat: bool,
+    pub(crate) build_mock: bool,
+    pub(crate) one_way_methods: Vec<String>,
+    pub(crate) codec_path: Option<String>,
+    file_descriptor_set_path: Option<PathBuf>,
+}
 
 pub fn configure() -> Builder {
-    Builder {}
+    Builder::default()
 }
 
 impl Builder {
+    /// Generate routers wrapping the tonic server traits instead of fabric-rpc traits.
+    /// tonic client and server code is emitted in the same file, and the fabric-rpc
+    /// modules are renamed to `<service>_fabric_client` and `<service>_fabric_server`
+    /// to avoid clashing with the tonic ones.
+    pub fn tonic_compat(mut self, enable: bool) -> Self {
+        self.tonic_compat = enable;
+        self
+    }
+
+    /// Generate a `<Service>ClientApi` trait implemented by the client,
+    /// and a `Mock<Service>Client` with per method canned responses.
+    pub fn build_mock(mut self, enable: bool) -> Self {
+        self.build_mock = enable;
+        self
+    }
+
+    /// Mark a method as one-way, given as `package.Service.Method` with proto names.
+    /// The generated client sends it without waiting for a reply and the
+    /// service trait method returns `()`. Methods returning `google.protobuf.Empty`
+    /// stay unary unless marked, so their errors still reach the caller.
+    pub fn one_way_method(mut self, path: impl Into<String>) -> Self {
+        self.one_way_methods.push(path.into());
+        self
+    }
+
+    /// Encode bodies with this codec instead of prost, given as a type path like
+    /// `fabric_rpc_rs::codec::JsonCodec`. The type must implement `Default` and
+    /// `fabric_rpc_rs::codec::MessageCodec` for all messages of the services.
+    /// Clients send its content type and servers reject requests in any other.
+    pub fn codec_path(mut self, path: impl Into<String>) -> Self {
+        self.codec_path = Some(path.into());
+        self
+    }
+
+    /// Write the encoded `FileDescriptorSet` of the compiled protos to this path.
+    /// It can be registered on the fabric-rpc reflection service with `include_bytes!`.
+    pub fn file_descriptor_set_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.file_descriptor_set_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
     /// Compile the .proto files and execute code generation.
     pub fn compile(
         self,
@@ -23,6 +74,9 @@ impl Builder {
     ) -> io::Result<()> {
         //self.compile_with_config(Config::new(), protos, includes)
         let mut config = Config::new();
+        if let Some(path) = self.file_descriptor_set_path.as_ref() {
+            config.file_descriptor_set_path(path);
+        }
         // add generator
         config.service_generator(self.service_generator());
         config.compile_protos(protos, includes)?;
@@ -31,7 +85,7 @@ impl Builder {
 
     // turn builder into generator
     pub fn service_generator(self) -> Box<dyn prost_build::ServiceGenerator> {
-        Box::new(ServiceGenerator::new())
+        Box::new(ServiceGenerator::new(self))
     }
 }
 
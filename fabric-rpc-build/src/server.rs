@@ -1,22 +1,35 @@
 // generate server code
+use std::collections::HashSet;
+
 use convert_case::{Case, Casing};
 use proc_macro2::TokenStream;
 use quote::{format_ident, quote};
 
-pub fn generate_internal(service: &prost_build::Service) -> TokenStream {
+use crate::code_gen::message_type;
+
+pub fn generate_internal(
+    service: &prost_build::Service,
+    one_way: &HashSet<String>,
+    codec: &TokenStream,
+) -> TokenStream {
     let service_ident = quote::format_ident!("{}Service", service.name);
     let server_mod = quote::format_ident!("{}_server", service.name.to_case(Case::Snake));
     let service_router_ident = quote::format_ident!("{}ServiceRouter", service.name);
 
     let service_name = format!("{}.{}", service.package, service.name);
 
-    let trait_methods = generate_service_trait_methods(service);
+    let trait_methods = generate_service_trait_methods(service, one_way);
 
-    let routing_code = generate_routing_branches(service);
+    let routing_code = generate_routing_branches(service, one_way, codec);
+    let descriptor = generate_descriptor(service);
     // print!("{}", routing_code);
     quote! {
       pub mod #server_mod{
-        use fabric_rpc_rs::server::{encode_proto, parse_proto, Service};
+        use fabric_rpc_rs::descriptor::ServiceDescriptor;
+        use fabric_rpc_rs::codec::{Codec, MessageCodec};
+        use fabric_rpc_rs::server::Service;
+
+        #descriptor
 
         // TODO: attr not work with quote
         //#![allow(unused_variables, dead_code, missing_docs)]
@@ -43,12 +56,92 @@ pub fn generate_internal(service: &prost_build::Service) -> TokenStream {
                 String::from(#service_name)
             }
 
+            fn descriptor(&self) -> Option<&'static ServiceDescriptor> {
+                Some(&SERVICE_DESCRIPTOR)
+            }
+
+            fn content_type(&self) -> &'static str {
+                <#codec>::default().content_type()
+            }
+
+            #[must_use]
+            async fn handle_request(
+                &self,
+                url: String,
+                request: bytes::Bytes,
+            ) -> std::result::Result<bytes::Bytes, tonic::Status> {
+                match url.as_str() {
+                   #routing_code
+                    _ => Err(tonic::Status::unimplemented("url not found")),
+                }
+            }
+        }
+
+      }
+    }
+}
+
+// router that dispatches to the tonic generated server trait,
+// so one impl can be served by both tonic and fabric-rpc.
+pub fn generate_tonic_router(
+    service: &prost_build::Service,
+    one_way: &HashSet<String>,
+    codec: &TokenStream,
+) -> TokenStream {
+    let tonic_mod = quote::format_ident!("{}_server", service.name.to_case(Case::Snake));
+    let tonic_trait = quote::format_ident!("{}", service.name);
+    let server_mod = quote::format_ident!("{}_fabric_server", service.name.to_case(Case::Snake));
+    let service_router_ident = quote::format_ident!("{}TonicRouter", service.name);
+
+    let service_name = format!("{}.{}", service.package, service.name);
+
+    let routing_code = generate_tonic_routing_branches(service, one_way, codec);
+    let descriptor = generate_descriptor(service);
+    quote! {
+      pub mod #server_mod{
+        use fabric_rpc_rs::descriptor::ServiceDescriptor;
+        use fabric_rpc_rs::codec::{Codec, MessageCodec};
+        use fabric_rpc_rs::server::Service;
+        use super::#tonic_mod::#tonic_trait;
+
+        #descriptor
+
+        // Router used for routing into the tonic service trait
+        pub struct #service_router_ident<T: #tonic_trait> {
+            svc: std::sync::Arc<T>,
+        }
+
+        impl<T: #tonic_trait> #service_router_ident<T> {
+          pub fn new(svc: T) -> #service_router_ident<T> {
+            Self::from_arc(std::sync::Arc::new(svc))
+          }
+
+          // share the impl with the tonic server, see its from_arc
+          pub fn from_arc(svc: std::sync::Arc<T>) -> #service_router_ident<T> {
+            #service_router_ident { svc }
+          }
+        }
+
+        #[tonic::async_trait]
+        impl<T: #tonic_trait> Service for #service_router_ident<T> {
+            fn name(&self) -> String {
+                String::from(#service_name)
+            }
+
+            fn descriptor(&self) -> Option<&'static ServiceDescriptor> {
+                Some(&SERVICE_DESCRIPTOR)
+            }
+
+            fn content_type(&self) -> &'static str {
+                <#codec>::default().content_type()
+            }
+
             #[must_use]
             async fn handle_request(
                 &self,
                 url: String,
-                request: &[u8],
-            ) -> std::result::Result<Vec<u8>, tonic::Status> {
+                request: bytes::Bytes,
+            ) -> std::result::Result<bytes::Bytes, tonic::Status> {
                 match url.as_str() {
                    #routing_code
                     _ => Err(tonic::Status::unimplemented("url not found")),
@@ -60,7 +153,52 @@ pub fn generate_internal(service: &prost_build::Service) -> TokenStream {
     }
 }
 
-fn generate_service_trait_methods(service: &prost_build::Service) -> TokenStream {
+// static descriptor of the service and the methods the router serves
+fn generate_descriptor(service: &prost_build::Service) -> TokenStream {
+    let service_name = format!("{}.{}", service.package, service.name);
+    let mut methods = TokenStream::new();
+    for method in &service.methods {
+        if method.client_streaming || method.server_streaming {
+            // not routed, so not listed either
+            continue;
+        }
+        let name = &method.proto_name;
+        let url = format!("/{}.{}/{}", service.package, service.name, method.name);
+        let input_type = method.input_proto_type.trim_start_matches('.');
+        let output_type = method.output_proto_type.trim_start_matches('.');
+        let client_streaming = method.client_streaming;
+        let server_streaming = method.server_streaming;
+        let idempotency = match method.options.idempotency_level {
+            Some(1) => quote!(NoSideEffects),
+            Some(2) => quote!(Idempotent),
+            _ => quote!(Unknown),
+        };
+        methods.extend(quote! {
+            fabric_rpc_rs::descriptor::MethodDescriptor {
+                name: #name,
+                url: #url,
+                input_type: #input_type,
+                output_type: #output_type,
+                streaming: fabric_rpc_rs::descriptor::StreamingKind::new(
+                    #client_streaming,
+                    #server_streaming,
+                ),
+                idempotency: fabric_rpc_rs::descriptor::Idempotency::#idempotency,
+            },
+        });
+    }
+    quote! {
+        pub static SERVICE_DESCRIPTOR: ServiceDescriptor = ServiceDescriptor {
+            name: #service_name,
+            methods: &[#methods],
+        };
+    }
+}
+
+fn generate_service_trait_methods(
+    service: &prost_build::Service,
+    one_way: &HashSet<String>,
+) -> TokenStream {
     let mut stream = TokenStream::new();
     for method in &service.methods {
         if method.client_streaming || method.server_streaming {
@@ -68,17 +206,26 @@ fn generate_service_trait_methods(service: &prost_build::Service) -> TokenStream
             continue;
         }
         let ident = format_ident!("{}", method.name);
-        let request_type = format_ident!("{}", method.input_type);
-        let response_type = format_ident!("{}", method.output_type);
+        let request_type = message_type(&method.input_type);
+        // one-way calls have nobody to reply to
+        let response_type = if one_way.contains(&method.name) {
+            quote!(())
+        } else {
+            message_type(&method.output_type)
+        };
         let method_desc = quote! {
-          async fn #ident(&self, request: super::#request_type) -> Result<super::#response_type, tonic::Status>;
+          async fn #ident(&self, request: #request_type) -> Result<#response_type, tonic::Status>;
         };
         stream.extend(method_desc);
     }
     stream
 }
 
-fn generate_routing_branches(service: &prost_build::Service) -> TokenStream {
+fn generate_routing_branches(
+    service: &prost_build::Service,
+    one_way: &HashSet<String>,
+    codec: &TokenStream,
+) -> TokenStream {
     let mut stream = TokenStream::new();
     for method in &service.methods {
         if method.client_streaming || method.server_streaming {
@@ -86,12 +233,64 @@ fn generate_routing_branches(service: &prost_build::Service) -> TokenStream {
             continue;
         }
         let ident = format_ident!("{}", method.name);
+        let request_type = message_type(&method.input_type);
+        let response_type = message_type(&method.output_type);
         let url = format!("/{}.{}/{}", service.package, service.name, method.name);
+        if one_way.contains(&method.name) {
+            stream.extend(quote! {
+              #url => {
+                let req = MessageCodec::<#request_type>::decode(&<#codec>::default(), &request)?;
+                self.svc.#ident(req).await?;
+                return Ok(bytes::Bytes::new());
+            }
+            });
+            continue;
+        }
         let routing_branch = quote! {
           #url => {
-            let req = parse_proto(request)?;
+            let codec = <#codec>::default();
+            let req = MessageCodec::<#request_type>::decode(&codec, &request)?;
             let resp = self.svc.#ident(req).await?;
-            return encode_proto(&resp);
+            return MessageCodec::<#response_type>::encode(&codec, &resp);
+        }
+        };
+        stream.extend(routing_branch);
+    }
+    stream
+}
+
+fn generate_tonic_routing_branches(
+    service: &prost_build::Service,
+    one_way: &HashSet<String>,
+    codec: &TokenStream,
+) -> TokenStream {
+    let mut stream = TokenStream::new();
+    for method in &service.methods {
+        if method.client_streaming || method.server_streaming {
+            // do not support streaming
+            continue;
+        }
+        let ident = format_ident!("{}", method.name);
+        let request_type = message_type(&method.input_type);
+        let response_type = message_type(&method.output_type);
+        let url = format!("/{}.{}/{}", service.package, service.name, method.name);
+        if one_way.contains(&method.name) {
+            // tonic still returns a response, it is dropped
+            stream.extend(quote! {
+              #url => {
+                let req = MessageCodec::<#request_type>::decode(&<#codec>::default(), &request)?;
+                self.svc.#ident(tonic::Request::new(req)).await?;
+                return Ok(bytes::Bytes::new());
+            }
+            });
+            continue;
+        }
+        let routing_branch = quote! {
+          #url => {
+            let codec = <#codec>::default();
+            let req = MessageCodec::<#request_type>::decode(&codec, &request)?;
+            let resp = self.svc.#ident(tonic::Request::new(req)).await?;
+            return MessageCodec::<#response_type>::encode(&codec, resp.get_ref());
         }
         };
         stream.extend(routing_branch);
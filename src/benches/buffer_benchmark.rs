@@ -0,0 +1,74 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use prost::Message;
+
+use fabric_rpc_rs::{buffer, fabricrpc_header::RequestHeader};
+
+// counts allocations so the bench can report them next to the timings
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const MSGS: usize = 1000;
+
+fn header() -> RequestHeader {
+    RequestHeader {
+        url: String::from("/helloworld.Greeter/SayHello"),
+        content_type: String::from("application/protobuf"),
+        ..Default::default()
+    }
+}
+
+// a fresh vec per msg, as before
+fn encode_vec(h: &RequestHeader) -> Vec<Bytes> {
+    (0..MSGS).map(|_| Bytes::from(h.encode_to_vec())).collect()
+}
+
+fn encode_shared(h: &RequestHeader) -> Vec<Bytes> {
+    (0..MSGS).map(|_| buffer::encode(h)).collect()
+}
+
+fn count_allocs(f: impl FnOnce() -> Vec<Bytes>) -> usize {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let out = f();
+    let n = ALLOCS.load(Ordering::Relaxed) - before;
+    drop(out);
+    n
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let h = header();
+    println!(
+        "allocations per {} headers: vec {}, shared buffer {}",
+        MSGS,
+        count_allocs(|| encode_vec(&h)),
+        count_allocs(|| encode_shared(&h))
+    );
+
+    let mut group = c.benchmark_group("header_encode");
+    group.bench_function("vec", |b| b.iter(|| encode_vec(&h)));
+    group.bench_function("shared_buffer", |b| b.iter(|| encode_shared(&h)));
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
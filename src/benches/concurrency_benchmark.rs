@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use fabric_rpc_rs::{
+    client::Client2,
+    server::{Server, Service},
+};
+use tokio::{runtime::Runtime, sync::oneshot};
+use windows::core::HSTRING;
+
+// calls issued at once on one connection per iteration
+const CALLS: usize = 8;
+const HANDLER_DELAY: Duration = Duration::from_millis(5);
+
+// handler that waits like a typical io bound rpc
+struct DelayService {}
+
+#[tonic::async_trait]
+impl Service for DelayService {
+    fn name(&self) -> String {
+        String::from("bench.Delay")
+    }
+
+    async fn handle_request(
+        &self,
+        _url: String,
+        _request: Bytes,
+    ) -> std::result::Result<Bytes, tonic::Status> {
+        tokio::time::sleep(HANDLER_DELAY).await;
+        Ok(Bytes::new())
+    }
+}
+
+async fn burst(client: &Client2) {
+    let calls =
+        (0..CALLS).map(|_| client.request::<()>(String::from("/bench.Delay/delay"), &(), 5000));
+    for resp in futures::future::join_all(calls).await {
+        resp.unwrap();
+    }
+}
+
+fn criterion_connection_concurrency(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("connection_concurrency");
+    // limit 1 is the old one request at a time behavior
+    for (port, limit) in [(12360, 1), (12361, CALLS)] {
+        let (stoptx, stoprx) = oneshot::channel::<()>();
+        let server = rt.spawn(async move {
+            let mut svr = Server::default();
+            svr.connection_concurrency_limit(limit);
+            svr.add_service(DelayService {});
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+        let client = rt
+            .block_on(Client2::connect(HSTRING::from(format!(
+                "localhost:{}+/",
+                port
+            ))))
+            .unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(limit), &client, |b, client| {
+            b.to_async(&rt).iter(|| burst(client));
+        });
+
+        stoptx.send(()).unwrap();
+        rt.block_on(server).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_connection_concurrency);
+criterion_main!(benches);
@@ -70,7 +70,7 @@ async fn start_transport_server() {
                 let mut reply_header = hello.clone();
                 reply_header.extend(header);
                 let mut reply_body = hello;
-                reply_body.extend(body);
+                reply_body.extend_from_slice(&body);
 
                 let reply = Message::create(reply_header, reply_body);
                 req.complete(reply);
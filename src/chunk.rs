@@ -0,0 +1,526 @@
+// Chunking of bodies larger than the transport max msg size.
+// A large request body is sent as frames, each acked by the server, and
+// dispatched once reassembled. A large reply comes back as its first frame
+// and the client pulls the rest by transfer id. Every frame carries the
+// total size, so the receiver rejects bodies over its limit up front.
+// Transfers the other side gives up on are dropped once idle for the
+// transfer timeout, which frees their part of the limit.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use prost::Message;
+use tonic::Status;
+
+use crate::{
+    compression::Encoding,
+    fabricrpc_header::{ChunkInfo, ReplyHeader, RequestHeader},
+    protocol,
+};
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024;
+// frames are cut further so the header fits in the msg
+pub const DEFAULT_FRAME_SIZE: usize = DEFAULT_MAX_MESSAGE_SIZE;
+pub const DEFAULT_MAX_SIZE: usize = 64 * 1024 * 1024;
+pub const DEFAULT_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// chunking settings of a client or server
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkConfig {
+    // max msg size of the transport, header and body
+    pub max_message_size: usize,
+    // bodies that do not fit in one msg are split in frames of at most this size
+    pub frame_size: usize,
+    // max bytes of bodies being reassembled or waiting to be pulled,
+    // per connection on the server, and max size of a decompressed body.
+    // Over it calls fail with ResourceExhausted.
+    pub max_size: usize,
+    // transfers without a frame for this long are dropped
+    pub transfer_timeout: Duration,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        ChunkConfig {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            frame_size: DEFAULT_FRAME_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+            transfer_timeout: DEFAULT_TRANSFER_TIMEOUT,
+        }
+    }
+}
+
+impl ChunkConfig {
+    // panics on settings that leave no room for a frame
+    pub(crate) fn check(&self) {
+        assert!(self.frame_size > 0, "frame size must be positive");
+        assert!(
+            self.max_message_size > reply_frame_header_len(),
+            "max msg size of {} bytes leaves no room for a reply frame",
+            self.max_message_size
+        );
+    }
+
+    // body size of the frames of a reply
+    pub(crate) fn reply_frame_size(&self) -> usize {
+        self.frame_size
+            .min(self.max_message_size - reply_frame_header_len())
+    }
+
+    // Body size of the frames of a request with header. The header goes with
+    // every frame, it fails with ResourceExhausted if it takes up the whole msg.
+    pub(crate) fn request_frame_size(
+        &self,
+        header: &RequestHeader,
+        total_size: usize,
+    ) -> Result<usize, Status> {
+        let mut frame_header = header.clone();
+        frame_header.chunk = Some(ChunkInfo {
+            transfer_id: u64::MAX,
+            seq: u32::MAX,
+            total_size: total_size as u64,
+        });
+        let len = frame_header.encoded_len();
+        match self.max_message_size.checked_sub(len) {
+            Some(room) if room > 0 => Ok(self.frame_size.min(room)),
+            _ => Err(Status::resource_exhausted(format!(
+                "request header of {} bytes does not fit in a msg of {} bytes",
+                len, self.max_message_size
+            ))),
+        }
+    }
+}
+
+// largest header of a reply frame
+fn reply_frame_header_len() -> usize {
+    ReplyHeader {
+        status_message: String::from("Ok"),
+        encoding: Encoding::Deflate.name().to_string(),
+        chunk: Some(ChunkInfo {
+            transfer_id: u64::MAX,
+            seq: u32::MAX,
+            total_size: u64::MAX,
+        }),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        capabilities: protocol::CAPABILITIES,
+        ..Default::default()
+    }
+    .encoded_len()
+}
+
+// frames of body, sharing its buffer
+pub(crate) fn frames(body: &Bytes, frame_size: usize) -> impl Iterator<Item = Bytes> + '_ {
+    assert!(frame_size > 0, "frame size must be positive");
+    (0..body.len())
+        .step_by(frame_size)
+        .map(move |start| body.slice(start..body.len().min(start + frame_size)))
+}
+
+fn over_limit(size: usize, buffered: usize, max_size: usize) -> Status {
+    Status::resource_exhausted(format!(
+        "chunked body of {} bytes over the limit, {} of {} bytes in use",
+        size, buffered, max_size
+    ))
+}
+
+// reassembles bodies from frames received in order
+#[derive(Debug)]
+pub(crate) struct Reassembler {
+    max_size: usize,
+    timeout: Duration,
+    buffered: usize,
+    transfers: HashMap<u64, Partial>,
+}
+
+#[derive(Debug)]
+struct Partial {
+    total: usize,
+    next_seq: u32,
+    buf: BytesMut,
+    expires: Instant,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: ChunkConfig) -> Reassembler {
+        Reassembler {
+            max_size: config.max_size,
+            timeout: config.transfer_timeout,
+            buffered: 0,
+            transfers: HashMap::new(),
+        }
+    }
+
+    // add a frame, returns the body once all frames are in
+    pub(crate) fn push(&mut self, info: &ChunkInfo, frame: Bytes) -> Result<Option<Bytes>, Status> {
+        let now = Instant::now();
+        self.evict(now);
+        let id = info.transfer_id;
+        let total = usize::try_from(info.total_size).unwrap_or(usize::MAX);
+        if info.seq == 0 {
+            // the whole body is reserved on the first frame
+            if total > self.max_size.saturating_sub(self.buffered) {
+                return Err(over_limit(total, self.buffered, self.max_size));
+            }
+            if self.transfers.contains_key(&id) {
+                self.remove(id);
+                return Err(Status::invalid_argument(format!(
+                    "chunk transfer {} started twice",
+                    id
+                )));
+            }
+            self.buffered += total;
+            self.transfers.insert(
+                id,
+                Partial {
+                    total,
+                    next_seq: 0,
+                    buf: BytesMut::with_capacity(total),
+                    expires: now + self.timeout,
+                },
+            );
+        }
+        let partial = match self.transfers.get_mut(&id) {
+            Some(partial) => partial,
+            None => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown chunk transfer {}",
+                    id
+                )))
+            }
+        };
+        if info.seq != partial.next_seq
+            || total != partial.total
+            || partial.buf.len() + frame.len() > partial.total
+        {
+            self.remove(id);
+            return Err(Status::invalid_argument(format!(
+                "frame {} of chunk transfer {} out of sequence",
+                info.seq, id
+            )));
+        }
+        partial.buf.extend_from_slice(&frame);
+        partial.next_seq += 1;
+        partial.expires = now + self.timeout;
+        if partial.buf.len() < partial.total {
+            return Ok(None);
+        }
+        Ok(self.remove(id).map(|p| p.buf.freeze()))
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Partial> {
+        let partial = self.transfers.remove(&id)?;
+        self.buffered -= partial.total;
+        Some(partial)
+    }
+
+    // drop transfers the sender gave up on
+    fn evict(&mut self, now: Instant) {
+        let expired: Vec<u64> = self
+            .transfers
+            .iter()
+            .filter(|(_, p)| p.expires <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+}
+
+// frame of a chunked reply
+#[derive(Debug)]
+pub(crate) struct ReplyFrame {
+    pub(crate) info: ChunkInfo,
+    pub(crate) encoding: String,
+    pub(crate) body: Bytes,
+}
+
+// Chunked replies waiting for the client to pull them. A reply is dropped
+// once its last frame is pulled, or when the client stops pulling.
+#[derive(Debug)]
+pub(crate) struct ReplyFrames {
+    max_size: usize,
+    timeout: Duration,
+    buffered: usize,
+    next_id: u64,
+    replies: HashMap<u64, PendingReply>,
+}
+
+#[derive(Debug)]
+struct PendingReply {
+    body: Bytes,
+    encoding: String,
+    frame_size: usize,
+    expires: Instant,
+}
+
+impl ReplyFrames {
+    pub(crate) fn new(config: ChunkConfig) -> ReplyFrames {
+        ReplyFrames {
+            max_size: config.max_size,
+            timeout: config.transfer_timeout,
+            buffered: 0,
+            next_id: 0,
+            replies: HashMap::new(),
+        }
+    }
+
+    // keep the reply to be pulled, returns its first frame
+    pub(crate) fn start(
+        &mut self,
+        body: Bytes,
+        encoding: String,
+        frame_size: usize,
+    ) -> Result<ReplyFrame, Status> {
+        assert!(frame_size > 0, "frame size must be positive");
+        let now = Instant::now();
+        self.evict(now);
+        if body.len() > self.max_size.saturating_sub(self.buffered) {
+            return Err(over_limit(body.len(), self.buffered, self.max_size));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.buffered += body.len();
+        self.replies.insert(
+            id,
+            PendingReply {
+                body,
+                encoding,
+                frame_size,
+                expires: now + self.timeout,
+            },
+        );
+        self.frame(id, 0)
+    }
+
+    pub(crate) fn frame(&mut self, id: u64, seq: u32) -> Result<ReplyFrame, Status> {
+        let now = Instant::now();
+        self.evict(now);
+        let reply = match self.replies.get_mut(&id) {
+            Some(reply) => reply,
+            None => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown chunk transfer {}",
+                    id
+                )))
+            }
+        };
+        let start = seq as usize * reply.frame_size;
+        if start >= reply.body.len() {
+            return Err(Status::invalid_argument(format!(
+                "frame {} of chunk transfer {} out of range",
+                seq, id
+            )));
+        }
+        let end = reply.body.len().min(start + reply.frame_size);
+        let frame = ReplyFrame {
+            info: ChunkInfo {
+                transfer_id: id,
+                seq,
+                total_size: reply.body.len() as u64,
+            },
+            encoding: reply.encoding.clone(),
+            body: reply.body.slice(start..end),
+        };
+        reply.expires = now + self.timeout;
+        if end == reply.body.len() {
+            self.replies.remove(&id);
+            self.buffered -= end;
+        }
+        Ok(frame)
+    }
+
+    // drop replies the client stopped pulling
+    fn evict(&mut self, now: Instant) {
+        let buffered = &mut self.buffered;
+        self.replies.retain(|_, r| {
+            if r.expires > now {
+                return true;
+            }
+            *buffered -= r.body.len();
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use prost::Message;
+    use tonic::Code;
+
+    use crate::fabricrpc_header::{ChunkInfo, RequestHeader};
+
+    use super::{ChunkConfig, Reassembler, ReplyFrames, DEFAULT_MAX_MESSAGE_SIZE};
+
+    fn config(max_size: usize) -> ChunkConfig {
+        ChunkConfig {
+            max_size,
+            ..Default::default()
+        }
+    }
+
+    fn info(transfer_id: u64, seq: u32, total_size: usize) -> ChunkInfo {
+        ChunkInfo {
+            transfer_id,
+            seq,
+            total_size: total_size as u64,
+        }
+    }
+
+    #[test]
+    fn reassemble_test() {
+        let body = Bytes::from((0..100u8).collect::<Vec<_>>());
+        let frames: Vec<Bytes> = super::frames(&body, 30).collect();
+        assert_eq!(
+            vec![30, 30, 30, 10],
+            frames.iter().map(|f| f.len()).collect::<Vec<_>>()
+        );
+
+        let mut re = Reassembler::new(config(150));
+        // two transfers interleaved
+        for (seq, frame) in frames.iter().enumerate().take(3) {
+            assert!(re
+                .push(&info(1, seq as u32, 100), frame.clone())
+                .unwrap()
+                .is_none());
+        }
+        // 100 of 150 bytes reserved
+        let err = re.push(&info(2, 0, 60), body.slice(..30)).unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+        assert!(re
+            .push(&info(3, 0, 50), body.slice(..50))
+            .unwrap()
+            .is_some());
+        let done = re.push(&info(1, 3, 100), frames[3].clone()).unwrap();
+        assert_eq!(Some(body.clone()), done);
+
+        // limit is free again
+        assert!(re
+            .push(&info(4, 0, 150), body.slice(..30))
+            .unwrap()
+            .is_none());
+        // skipped frame drops the transfer
+        let err = re.push(&info(4, 2, 150), body.slice(..30)).unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+        let err = re.push(&info(4, 1, 150), body.slice(..30)).unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+        // over the limit on its own
+        let err = re.push(&info(5, 0, 151), body.slice(..30)).unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+    }
+
+    #[test]
+    fn reply_frames_test() {
+        let body = Bytes::from((0..100u8).collect::<Vec<_>>());
+        let mut replies = ReplyFrames::new(config(150));
+        let first = replies
+            .start(body.clone(), String::from("gzip"), 40)
+            .unwrap();
+        assert_eq!(0, first.info.seq);
+        assert_eq!(100, first.info.total_size);
+        assert_eq!("gzip", first.encoding);
+
+        let err = replies.start(body.clone(), String::new(), 40).unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+
+        let mut re = Reassembler::new(config(100));
+        assert!(re.push(&first.info, first.body).unwrap().is_none());
+        let id = first.info.transfer_id;
+        let second = replies.frame(id, 1).unwrap();
+        assert!(re.push(&second.info, second.body).unwrap().is_none());
+        let last = replies.frame(id, 2).unwrap();
+        assert_eq!(Some(body), re.push(&last.info, last.body).unwrap());
+
+        // dropped after the last frame
+        let err = replies.frame(id, 0).unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+    }
+
+    #[test]
+    fn abandoned_transfer_test() {
+        let body = Bytes::from((0..100u8).collect::<Vec<_>>());
+        let config = ChunkConfig {
+            max_size: 100,
+            transfer_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        // sender gives up after the first frame
+        let mut re = Reassembler::new(config);
+        assert!(re
+            .push(&info(1, 0, 100), body.slice(..30))
+            .unwrap()
+            .is_none());
+        let err = re.push(&info(2, 0, 100), body.slice(..30)).unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(re
+            .push(&info(2, 0, 100), body.slice(..30))
+            .unwrap()
+            .is_none());
+        let err = re.push(&info(1, 1, 100), body.slice(30..60)).unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+
+        // client never pulls the rest
+        let mut replies = ReplyFrames::new(config);
+        let first = replies.start(body.clone(), String::new(), 40).unwrap();
+        let err = replies.start(body.clone(), String::new(), 40).unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+        std::thread::sleep(Duration::from_millis(100));
+        replies.start(body.clone(), String::new(), 40).unwrap();
+        let err = replies.frame(first.info.transfer_id, 1).unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+    }
+
+    #[test]
+    fn frame_size_test() {
+        let config = ChunkConfig::default();
+        let mut header = RequestHeader {
+            url: String::from("/a.B/c"),
+            ..Default::default()
+        };
+        let small = config.request_frame_size(&header, 1 << 20).unwrap();
+        assert!(small < DEFAULT_MAX_MESSAGE_SIZE);
+        assert!(config.reply_frame_size() < DEFAULT_MAX_MESSAGE_SIZE);
+
+        // the frame and its header fit in a msg
+        header
+            .metadata
+            .insert(String::from("padding"), "p".repeat(600));
+        let large = config.request_frame_size(&header, 1 << 20).unwrap();
+        assert!(large < small - 600);
+        let mut frame_header = header.clone();
+        frame_header.chunk = Some(info(u64::MAX, u32::MAX, 1 << 20));
+        assert!(frame_header.encoded_len() + large <= DEFAULT_MAX_MESSAGE_SIZE);
+
+        header.metadata.insert(
+            String::from("padding"),
+            "p".repeat(DEFAULT_MAX_MESSAGE_SIZE),
+        );
+        let err = config.request_frame_size(&header, 1 << 20).unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+
+        // frame size caps the room
+        let config = ChunkConfig {
+            frame_size: 100,
+            ..Default::default()
+        };
+        assert_eq!(100, config.reply_frame_size());
+    }
+
+    #[test]
+    #[should_panic(expected = "no room")]
+    fn small_message_size_test() {
+        ChunkConfig {
+            max_message_size: 16,
+            ..Default::default()
+        }
+        .check();
+    }
+}
@@ -1,29 +1,230 @@
 // client for fabric-rpc protocol
 
-use std::io::Cursor;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
+use bytes::Bytes;
 use fabric_base::{
     FabricCommon::FabricTransport::FABRIC_TRANSPORT_SETTINGS, FABRIC_SECURITY_CREDENTIALS,
     FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
 };
 use prost::Message;
-use tonic::{Code, Status};
+use tokio::sync::OnceCell;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use tonic::{async_trait, Code, Status};
+use tracing::{field, Instrument};
 use windows::core::{Error, HSTRING};
 
 use crate::{
+    buffer,
+    chunk::{self, ChunkConfig, Reassembler},
     client_tr::ClientTransport,
-    fabricrpc_header::{ReplyHeader, RequestHeader},
+    codec::{self, Codec, MessageCodec, ProstCodec},
+    compression::CompressionConfig,
+    context::RequestContext,
+    fabricrpc_header::{ChunkInfo, ReplyHeader, RequestHeader},
+    metrics::{CallOutcome, MetricsRecorder, Side},
+    middleware::FabricRequest,
+    protocol::{self, PeerInfo, CAP_CHUNKING, CAP_COMPRESSION, CAP_CONTENT_TYPE},
     sys::MessageViewer,
+    trace::{self, TraceContext},
 };
 
-// Client is a wrapper for the transport to implement rpc protocol
+// Channel sends an encoded request and returns the encoded reply body.
+// Client2 is the FabricTransport channel. Other impls can pool or load balance
+// connections, or fake the server in tests.
+#[async_trait]
+pub trait Channel: Send + Sync {
+    async fn call(
+        &self,
+        header: RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<Bytes, Status>;
+
+    // send without waiting for a reply
+    async fn send_one_way(&self, _header: RequestHeader, _body: Bytes) -> Result<(), Status> {
+        Err(Status::unimplemented(
+            "channel does not support one-way calls",
+        ))
+    }
+}
+
+#[async_trait]
+impl<C: Channel + ?Sized> Channel for Arc<C> {
+    async fn call(
+        &self,
+        header: RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<Bytes, Status> {
+        (**self).call(header, body, timoutmilliseconds).await
+    }
+
+    async fn send_one_way(&self, header: RequestHeader, body: Bytes) -> Result<(), Status> {
+        (**self).send_one_way(header, body).await
+    }
+}
+
+// Interceptor can inspect or modify the request header before it is sent,
+// or reject the call with an error.
+pub trait Interceptor: Send + Sync {
+    fn call(&self, header: RequestHeader) -> Result<RequestHeader, Status>;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(RequestHeader) -> Result<RequestHeader, Status> + Send + Sync,
+{
+    fn call(&self, header: RequestHeader) -> Result<RequestHeader, Status> {
+        self(header)
+    }
+}
+
+// channel that runs the interceptor before every call
+pub struct InterceptedChannel<C, I> {
+    inner: C,
+    interceptor: I,
+}
+
+impl<C: Channel, I: Interceptor> InterceptedChannel<C, I> {
+    pub fn new(inner: C, interceptor: I) -> InterceptedChannel<C, I> {
+        InterceptedChannel { inner, interceptor }
+    }
+}
+
+#[async_trait]
+impl<C: Channel, I: Interceptor> Channel for InterceptedChannel<C, I> {
+    async fn call(
+        &self,
+        header: RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<Bytes, Status> {
+        let header = self.interceptor.call(header)?;
+        self.inner.call(header, body, timoutmilliseconds).await
+    }
+
+    async fn send_one_way(&self, header: RequestHeader, body: Bytes) -> Result<(), Status> {
+        let header = self.interceptor.call(header)?;
+        self.inner.send_one_way(header, body).await
+    }
+}
+
+// send the msg over the channel and returns the proto reply
+pub async fn unary<C, T>(
+    channel: &C,
+    url: String,
+    msg: &(impl Message + Default),
+    timoutmilliseconds: u32,
+) -> Result<T, Status>
+where
+    C: Channel + ?Sized,
+    T: Message + Default,
+{
+    unary_with_codec(channel, url, msg, timoutmilliseconds, &ProstCodec).await
+}
+
+// unary call with the bodies encoded by codec instead of prost
+pub async fn unary_with_codec<C, K, Req, Resp>(
+    channel: &C,
+    url: String,
+    msg: &Req,
+    timoutmilliseconds: u32,
+    codec: &K,
+) -> Result<Resp, Status>
+where
+    C: Channel + ?Sized,
+    K: MessageCodec<Req> + MessageCodec<Resp>,
+{
+    let reqheader = RequestHeader {
+        url,
+        content_type: Codec::content_type(codec).to_string(),
+        ..Default::default()
+    };
+
+    let bodybuf = MessageCodec::<Req>::encode(codec, msg)?;
+    let body_ret = channel.call(reqheader, bodybuf, timoutmilliseconds).await?;
+    // a reply the client cannot read is not the caller's fault
+    MessageCodec::<Resp>::decode(codec, &body_ret).map_err(|e| Status::internal(e.message()))
+}
+
+// send the msg over the channel without waiting for a reply.
+// Errors only mean the msg could not be sent, not that the server failed it.
+pub async fn one_way<C>(
+    channel: &C,
+    url: String,
+    msg: &(impl Message + Default),
+) -> Result<(), Status>
+where
+    C: Channel + ?Sized,
+{
+    one_way_with_codec(channel, url, msg, &ProstCodec).await
+}
+
+// one-way call with the body encoded by codec instead of prost
+pub async fn one_way_with_codec<C, K, Req>(
+    channel: &C,
+    url: String,
+    msg: &Req,
+    codec: &K,
+) -> Result<(), Status>
+where
+    C: Channel + ?Sized,
+    K: MessageCodec<Req>,
+{
+    let reqheader = RequestHeader {
+        url,
+        content_type: codec.content_type().to_string(),
+        ..Default::default()
+    };
+
+    let bodybuf = codec.encode(msg)?;
+    channel.send_one_way(reqheader, bodybuf).await
+}
+
+// one-way calls have no timeout of their own
+const HANDSHAKE_TIMEOUT_MILLIS: u32 = 5000;
+
+// Client is a wrapper for the transport to implement rpc protocol.
+// Clones share the connection.
 // TODO: support client close
+#[derive(Clone)]
 pub struct Client2 {
-    tr: ClientTransport,
+    tr: Arc<ClientTransport>,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+    compression: CompressionConfig,
+    chunking: ChunkConfig,
+    // of the transport, chunking cannot go over it
+    max_message_size: usize,
+    // ids of chunked requests, shared by clones on the connection
+    next_transfer: Arc<AtomicU64>,
+    // what the server speaks, from the handshake before the first call
+    peer: Arc<OnceCell<PeerInfo>>,
 }
 
 impl Client2 {
     pub async fn connect(addr: HSTRING) -> Result<Client2, Error> {
+        Client2::connect_with_chunking(addr, ChunkConfig::default()).await
+    }
+
+    // the transport takes msgs up to the max msg size of config
+    pub async fn connect_with_chunking(
+        addr: HSTRING,
+        config: ChunkConfig,
+    ) -> Result<Client2, Error> {
+        config.check();
         let creds = FABRIC_SECURITY_CREDENTIALS {
             Kind: FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
             Value: std::ptr::null_mut(),
@@ -31,7 +232,7 @@ impl Client2 {
         let settings = FABRIC_TRANSPORT_SETTINGS {
             OperationTimeoutInSeconds: 10,
             KeepAliveTimeoutInSeconds: 10,
-            MaxMessageSize: 1024,
+            MaxMessageSize: config.max_message_size as u32,
             MaxConcurrentCalls: 10,
             MaxQueueSize: 10,
             SecurityCredentials: &creds,
@@ -41,26 +242,265 @@ impl Client2 {
         let timoutmilliseconds = 100000;
         tr.open(timoutmilliseconds).await?;
         tr.connect().await;
-        Ok(Client2 { tr })
+        Ok(Client2 {
+            tr: Arc::new(tr),
+            metrics: None,
+            compression: CompressionConfig::default(),
+            chunking: config,
+            max_message_size: config.max_message_size,
+            next_transfer: Arc::new(AtomicU64::new(0)),
+            peer: Arc::new(OnceCell::new()),
+        })
+    }
+
+    // record calls made through this client and its clones
+    pub fn with_metrics(mut self, recorder: Arc<dyn MetricsRecorder>) -> Client2 {
+        self.metrics = Some(recorder);
+        self
+    }
+
+    // encoding of request bodies and the reply encodings this client accepts.
+    // By default requests are not compressed and all encodings are accepted.
+    pub fn with_compression(mut self, config: CompressionConfig) -> Client2 {
+        self.compression = config;
+        self
+    }
+
+    // Request bodies that do not fit in one msg are sent in frames, and chunked
+    // replies are reassembled up to max_size. One-way msgs are never chunked.
+    // The max msg size cannot be over the one the client connected with.
+    pub fn with_chunking(mut self, config: ChunkConfig) -> Client2 {
+        config.check();
+        assert!(
+            config.max_message_size <= self.max_message_size,
+            "max msg size of {} bytes is over the {} bytes of the transport",
+            config.max_message_size,
+            self.max_message_size
+        );
+        self.chunking = config;
+        self
+    }
+
+    // protocol of the server, none before the first call
+    pub fn peer(&self) -> Option<PeerInfo> {
+        self.peer.get().copied()
     }
 
     // send the msg and returns the proto reply
     pub async fn request<T: Message + std::default::Default>(
         &self,
         url: String,
-        msg: &impl Message,
+        msg: &(impl Message + Default),
         timoutmilliseconds: u32,
     ) -> Result<T, Status> {
-        let reqheader = RequestHeader { url };
+        unary(self, url, msg, timoutmilliseconds).await
+    }
+
+    // send the msg encoded by codec, the service at url must use the same codec
+    pub async fn request_with_codec<K, Req, Resp>(
+        &self,
+        url: String,
+        msg: &Req,
+        timoutmilliseconds: u32,
+        codec: &K,
+    ) -> Result<Resp, Status>
+    where
+        K: MessageCodec<Req> + MessageCodec<Resp>,
+    {
+        unary_with_codec(self, url, msg, timoutmilliseconds, codec).await
+    }
+
+    // send the msg without waiting for a reply
+    pub async fn send_one_way(
+        &self,
+        url: String,
+        msg: &(impl Message + Default),
+    ) -> Result<(), Status> {
+        one_way(self, url, msg).await
+    }
+
+    // stream of notifications the server pushes to url, see Notifier.
+    // Only notifications arriving after this call are received.
+    pub fn subscribe<T: Message + Default>(
+        &self,
+        url: &str,
+    ) -> impl Stream<Item = Result<T, Status>> + Send + 'static {
+        let url = url.to_string();
+        BroadcastStream::new(self.tr.subscribe()).filter_map(move |item| match item {
+            Ok(msg) => {
+                let header = RequestHeader::decode(msg.header.clone()).ok()?;
+                if header.url != url {
+                    return None;
+                }
+                Some(T::decode(msg.body).map_err(|e| Status::internal(e.to_string())))
+            }
+            Err(BroadcastStreamRecvError::Lagged(n)) => Some(Err(Status::data_loss(format!(
+                "subscriber lagged, {} notifications dropped",
+                n
+            )))),
+        })
+    }
+}
+
+// Trace of an outgoing call. Continues a traceparent already in the metadata,
+// else the trace of the handler making the call, else starts a new one.
+fn start_trace(header: &mut RequestHeader) -> TraceContext {
+    let parent = TraceContext::from_metadata(&header.metadata)
+        .or_else(|| RequestContext::current().and_then(|ctx| ctx.trace_context()));
+    let trace = parent.map_or_else(TraceContext::new_root, |p| p.child());
+    trace.inject(&mut header.metadata);
+    trace
+}
 
-        let mut headerbuf = Vec::new();
-        reqheader.encode(&mut headerbuf).unwrap();
+impl Client2 {
+    // Compress the body and tell the server which reply encodings work.
+    // Features the server does not speak are left out.
+    fn encode_request(
+        &self,
+        mut header: RequestHeader,
+        body: Bytes,
+        peer: &PeerInfo,
+    ) -> Result<(RequestHeader, Bytes), Status> {
+        header.protocol_version = protocol::PROTOCOL_VERSION;
+        header.capabilities = protocol::CAPABILITIES;
+        if !peer.supports(CAP_CONTENT_TYPE) {
+            // older servers read every body as protobuf
+            if !codec::content_type_matches(codec::PROTOBUF, &header.content_type) {
+                return Err(Status::unimplemented(format!(
+                    "server does not support content type {}",
+                    header.content_type
+                )));
+            }
+            header.content_type.clear();
+        }
+        if !peer.supports(CAP_COMPRESSION) {
+            return Ok((header, body));
+        }
+        let (body, encoding) = self.compression.compress(body, None)?;
+        header.encoding = encoding;
+        header.accept_encoding = self.compression.accept_names();
+        Ok((header, body))
+    }
 
-        let mut bodybuf = Vec::new();
-        msg.encode(&mut bodybuf).unwrap();
+    async fn request_raw(
+        &self,
+        header: RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<Bytes, Status> {
+        // frames share the timeout of the call
+        let deadline = Instant::now() + Duration::from_millis(timoutmilliseconds as u64);
+        let peer = self.handshake(timoutmilliseconds).await?;
+        let (mut header, body) = self.encode_request(header, body, &peer)?;
+        // the header goes with every frame, the body gets the rest of the msg
+        let frame_size = if peer.supports(CAP_CHUNKING) {
+            header.max_reply_frame = self.chunking.reply_frame_size() as u32;
+            Some(self.chunking.request_frame_size(&header, body.len())?)
+        } else {
+            None
+        };
+        let (replyheader, body_ret) = match frame_size {
+            Some(frame_size) if body.len() > frame_size => {
+                self.send_frames(&header, body, frame_size, deadline)
+                    .await?
+            }
+            _ => {
+                self.round_trip(&header, body, remaining_millis(deadline)?)
+                    .await?
+            }
+        };
+        let body_ret = match replyheader.chunk {
+            Some(first) => self.pull_frames(&header, first, body_ret, deadline).await?,
+            None => body_ret,
+        };
+
+        self.compression
+            .decompress(&replyheader.encoding, &body_ret, self.chunking.max_size)
+    }
+
+    // send the body in frames, the reply of the last one is the reply of the call
+    async fn send_frames(
+        &self,
+        header: &RequestHeader,
+        body: Bytes,
+        frame_size: usize,
+        deadline: Instant,
+    ) -> Result<(ReplyHeader, Bytes), Status> {
+        let transfer_id = self.next_transfer.fetch_add(1, Ordering::Relaxed);
+        let mut reply = None;
+        for (seq, frame) in chunk::frames(&body, frame_size).enumerate() {
+            let mut frame_header = header.clone();
+            frame_header.chunk = Some(ChunkInfo {
+                transfer_id,
+                seq: seq as u32,
+                total_size: body.len() as u64,
+            });
+            reply = Some(
+                self.round_trip(&frame_header, frame, remaining_millis(deadline)?)
+                    .await?,
+            );
+        }
+        Ok(reply.unwrap())
+    }
+
+    // pull the rest of a chunked reply after its first frame
+    async fn pull_frames(
+        &self,
+        header: &RequestHeader,
+        first: ChunkInfo,
+        body: Bytes,
+        deadline: Instant,
+    ) -> Result<Bytes, Status> {
+        let mut frames = Reassembler::new(self.chunking);
+        let mut next = frames.push(&first, body)?;
+        let pull = RequestHeader {
+            url: header.url.clone(),
+            ..Default::default()
+        };
+        let mut seq = first.seq;
+        while next.is_none() {
+            seq += 1;
+            let mut pull = pull.clone();
+            pull.reply_chunk = Some(ChunkInfo { seq, ..first });
+            let (replyheader, frame) = self
+                .round_trip(&pull, Bytes::new(), remaining_millis(deadline)?)
+                .await?;
+            let info = replyheader
+                .chunk
+                .ok_or_else(|| Status::internal("reply frame without chunk info"))?;
+            next = frames.push(&info, frame)?;
+        }
+        Ok(next.unwrap())
+    }
+
+    // send one msg and wait for its reply, a reply status other than Ok is an error
+    async fn round_trip(
+        &self,
+        header: &RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<(ReplyHeader, Bytes), Status> {
+        let (replyheader, body_ret) = self.send_msg(header, body, timoutmilliseconds).await?;
+        let code = replyheader.status_code;
+        let code_enum = Code::from_i32(code);
+        if code_enum != Code::Ok {
+            return Err(Status::new(code_enum, replyheader.status_message));
+        }
+        Ok((replyheader, body_ret))
+    }
 
-        let msg = crate::sys::Message::create(headerbuf, bodybuf);
-        let reply = self.tr.request(timoutmilliseconds, &msg).await;
+    // send one msg and decode the reply header, whatever its status
+    async fn send_msg(
+        &self,
+        header: &RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<(ReplyHeader, Bytes), Status> {
+        let fut = {
+            let msg = crate::sys::Message::create(buffer::encode(header), body);
+            self.tr.request(timoutmilliseconds, &msg)
+        };
+        let reply = fut.await;
         if reply.is_err() {
             let e = reply.unwrap_err();
             return Err(Status::internal(format!(
@@ -72,30 +512,159 @@ impl Client2 {
 
         let reply = reply.unwrap();
 
-        let replyvw = MessageViewer::new(reply);
-        let header_ret = replyvw.get_header();
-        let body_ret = replyvw.get_body();
+        // the body shares the reply msg, no copy
+        let (header_ret, body_ret) = MessageViewer::new(reply).into_bytes();
 
-        let replyheader = ReplyHeader::decode(&mut Cursor::new(header_ret));
+        let replyheader = ReplyHeader::decode(header_ret);
 
         if let Err(err) = replyheader {
             return Err(Status::internal(err.to_string()));
         }
 
-        let replyheader = replyheader.unwrap();
-        let code = replyheader.status_code;
-        let status_msg = replyheader.status_message;
-        let code_enum = Code::from_i32(code);
-        let headerstatus = Status::new(code_enum, status_msg);
-        if headerstatus.code() != Code::Ok {
-            return Err(headerstatus);
-        }
+        Ok((replyheader.unwrap(), body_ret))
+    }
 
-        let replyout = T::decode(&mut Cursor::new(body_ret));
+    // Ask the server what it speaks, once per connection. Servers before
+    // versioning reply Unimplemented without a version, they are legacy.
+    // Transport errors are not cached, the next call tries again.
+    async fn handshake(&self, timoutmilliseconds: u32) -> Result<PeerInfo, Status> {
+        let peer = self
+            .peer
+            .get_or_try_init(|| async {
+                let header = RequestHeader {
+                    url: String::from(protocol::HANDSHAKE_URL),
+                    protocol_version: protocol::PROTOCOL_VERSION,
+                    capabilities: protocol::CAPABILITIES,
+                    ..Default::default()
+                };
+                let (replyheader, _) = self
+                    .send_msg(&header, Bytes::new(), timoutmilliseconds)
+                    .await?;
+                let peer = PeerInfo::of_reply(&replyheader);
+                tracing::debug!(
+                    version = peer.version,
+                    capabilities = peer.capabilities,
+                    "server protocol"
+                );
+                Ok::<_, Status>(peer)
+            })
+            .await?;
+        Ok(*peer)
+    }
+}
 
-        if let Err(err) = replyout {
-            return Err(Status::internal(err.to_string()));
+// timeout left for the next frame of a call
+fn remaining_millis(deadline: Instant) -> Result<u32, Status> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(Status::deadline_exceeded("chunked call timed out"));
+    }
+    Ok(left.as_millis().min(u32::MAX as u128) as u32)
+}
+
+#[async_trait]
+impl Channel for Client2 {
+    async fn call(
+        &self,
+        mut header: RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<Bytes, Status> {
+        let trace = start_trace(&mut header);
+        let span = tracing::info_span!(
+            "fabric_rpc.client",
+            url = %header.url,
+            trace_id = %trace.trace_id(),
+            span_id = %trace.span_id(),
+            request_size = body.len(),
+            reply_size = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        let url = header.url.clone();
+        let request_size = body.len();
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.call_started(Side::Client, &url);
+        }
+        let started = Instant::now();
+        let result = self
+            .request_raw(header, body, timoutmilliseconds)
+            .instrument(span.clone())
+            .await;
+        trace::record_result(&span, &result, started);
+        if let Some(metrics) = self.metrics.as_ref() {
+            let outcome = CallOutcome {
+                code: result.as_ref().map_or_else(|e| e.code(), |_| Code::Ok),
+                latency: started.elapsed(),
+                request_size,
+                reply_size: result.as_ref().map_or(0, |b| b.len()),
+            };
+            metrics.call_finished(Side::Client, &url, &outcome);
+        }
+        result
+    }
+
+    async fn send_one_way(&self, mut header: RequestHeader, body: Bytes) -> Result<(), Status> {
+        let trace = start_trace(&mut header);
+        tracing::debug!(
+            url = %header.url,
+            trace_id = %trace.trace_id(),
+            span_id = %trace.span_id(),
+            request_size = body.len(),
+            "sending one-way msg"
+        );
+        let url = header.url.clone();
+        let request_size = body.len();
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.call_started(Side::Client, &url);
+        }
+        let started = Instant::now();
+        let result = async {
+            let peer = self.handshake(HANDSHAKE_TIMEOUT_MILLIS).await?;
+            let (header, body) = self.encode_request(header, body, &peer)?;
+            let msg = crate::sys::Message::create(buffer::encode(&header), body);
+            self.tr.send(&msg).map_err(|e| {
+                Status::internal(format!(
+                    "client transport failed code: {} message:{}",
+                    e.code(),
+                    e.message()
+                ))
+            })
         }
-        Ok(replyout.unwrap())
+        .await;
+        // finished once sent, there is no reply
+        if let Some(metrics) = self.metrics.as_ref() {
+            let outcome = CallOutcome {
+                code: result.as_ref().map_or_else(|e| e.code(), |_| Code::Ok),
+                latency: started.elapsed(),
+                request_size,
+                reply_size: 0,
+            };
+            metrics.call_finished(Side::Client, &url, &outcome);
+        }
+        result
+    }
+}
+
+// lets tower layers wrap the client, see middleware::ServiceChannel
+// to use the stack from generated clients
+impl tower::Service<FabricRequest> for Client2 {
+    type Response = Bytes;
+    type Error = Status;
+    type Future = Pin<Box<dyn Future<Output = Result<Bytes, Status>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: FabricRequest) -> Self::Future {
+        let client = self.clone();
+        Box::pin(async move {
+            if req.one_way {
+                Channel::send_one_way(&client, req.header, req.body).await?;
+                return Ok(Bytes::new());
+            }
+            Channel::call(&client, req.header, req.body, req.timeout_milliseconds).await
+        })
     }
 }
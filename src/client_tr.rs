@@ -1,32 +1,45 @@
 // client transport
 
-use std::cell::RefCell;
+use std::{cell::RefCell, future::Future};
 
+use bytes::Bytes;
 use fabric_base::FabricCommon::FabricTransport::{
     CreateFabricTransportClient, IFabricTransportCallbackMessageHandler,
     IFabricTransportCallbackMessageHandler_Impl, IFabricTransportClient,
     IFabricTransportClientEventHandler, IFabricTransportClientEventHandler_Impl,
     IFabricTransportMessage, IFabricTransportMessageDisposer, FABRIC_TRANSPORT_SETTINGS,
 };
-use tokio::sync::oneshot::{self, Receiver, Sender};
+use tokio::sync::{
+    broadcast,
+    oneshot::{self, Receiver, Sender},
+};
 use windows::core::{implement, ComInterface, Error, HRESULT, HSTRING};
 
-use crate::{shared_tr::MsgDispoer, sys::AwaitableCallback};
+use crate::{
+    shared_tr::MsgDispoer,
+    sys::{AwaitableCallback, ContextWrapper, MessageViewer},
+};
+
+// one-way msgs buffered per subscriber before it lags
+const ONE_WAY_CAPACITY: usize = 100;
+
+// one-way msg sent by the server, shares the fabric msg
+#[derive(Debug, Clone)]
+pub struct OneWayMessage {
+    pub header: Bytes,
+    pub body: Bytes,
+}
 
 // required COM obj for client
 #[derive(Debug)]
 #[implement(IFabricTransportCallbackMessageHandler)]
-struct ClientMsgHandler {}
-
-impl ClientMsgHandler {
-    pub fn new() -> ClientMsgHandler {
-        ClientMsgHandler {}
-    }
+struct ClientMsgHandler {
+    tx: broadcast::Sender<OneWayMessage>,
 }
 
-impl Default for ClientMsgHandler {
-    fn default() -> Self {
-        Self::new()
+impl ClientMsgHandler {
+    pub fn new(tx: broadcast::Sender<OneWayMessage>) -> ClientMsgHandler {
+        ClientMsgHandler { tx }
     }
 }
 
@@ -34,8 +47,19 @@ impl Default for ClientMsgHandler {
 impl IFabricTransportCallbackMessageHandler_Impl for ClientMsgHandler {
     fn HandleOneWay(
         &self,
-        _message: ::core::option::Option<&IFabricTransportMessage>,
+        message: ::core::option::Option<&IFabricTransportMessage>,
     ) -> ::windows::core::Result<()> {
+        if let Some(msg) = message {
+            let vw = MessageViewer::new(msg.clone());
+            tracing::trace!(
+                header_size = vw.get_header().len(),
+                body_size = vw.body_len(),
+                "one-way msg received"
+            );
+            let (header, body) = vw.into_bytes();
+            // no subscribers is fine, the msg is dropped
+            let _ = self.tx.send(OneWayMessage { header, body });
+        }
         Ok(())
     }
 }
@@ -64,6 +88,7 @@ impl IFabricTransportClientEventHandler_Impl for ClientEvHandler {
     ) -> ::windows::core::Result<()> {
         let tx = self.conn_tx.take();
         if let Some(txx) = tx {
+            tracing::debug!("client connected");
             txx.send(()).unwrap();
         } else {
             panic!("Connect can only happen once")
@@ -78,8 +103,8 @@ impl IFabricTransportClientEventHandler_Impl for ClientEvHandler {
     ) -> ::windows::core::Result<()> {
         let tx = self.disconn_tx.take();
         if let Some(txx) = tx {
+            tracing::debug!(hr = ?error, "client disconnected");
             txx.send(error).unwrap();
-            println!("Client event disconnected");
         } else {
             panic!("Disconnect can only happen once")
         }
@@ -92,8 +117,13 @@ pub struct ClientTransport {
     c: IFabricTransportClient,
     conn_rx: Option<Receiver<()>>,
     disconn_rx: Option<Receiver<HRESULT>>,
+    one_way_tx: broadcast::Sender<OneWayMessage>,
 }
 
+// fabric client is thread safe
+unsafe impl Send for ClientTransport {}
+unsafe impl Sync for ClientTransport {}
+
 impl ClientTransport {
     pub fn new(
         settings: &FABRIC_TRANSPORT_SETTINGS,
@@ -101,9 +131,10 @@ impl ClientTransport {
     ) -> Result<ClientTransport, Error> {
         let (conn_tx, conn_rx) = oneshot::channel::<()>();
         let (disconn_tx, disconn_rx) = oneshot::channel::<HRESULT>();
+        let (one_way_tx, _) = broadcast::channel::<OneWayMessage>(ONE_WAY_CAPACITY);
 
         let notificationhandler: IFabricTransportCallbackMessageHandler =
-            ClientMsgHandler::new().into();
+            ClientMsgHandler::new(one_way_tx.clone()).into();
         let clienteventhandler: IFabricTransportClientEventHandler =
             ClientEvHandler::new(conn_tx, disconn_tx).into();
         let messagedisposer: IFabricTransportMessageDisposer = MsgDispoer::new().into();
@@ -123,9 +154,15 @@ impl ClientTransport {
             c: client,
             conn_rx: Some(conn_rx),
             disconn_rx: Some(disconn_rx),
+            one_way_tx,
         })
     }
 
+    // receive one-way msgs sent by the server from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<OneWayMessage> {
+        self.one_way_tx.subscribe()
+    }
+
     // wait for connection
     pub async fn connect(&mut self) {
         let rx = self.conn_rx.take();
@@ -156,16 +193,35 @@ impl ClientTransport {
         Ok(())
     }
 
-    pub async fn request(
+    // The request is started before the returned future is polled,
+    // so msg is not held across await and the future is Send.
+    pub fn request(
         &self,
         timoutmilliseconds: u32,
         msg: &IFabricTransportMessage,
-    ) -> Result<IFabricTransportMessage, Error> {
+    ) -> impl Future<Output = Result<IFabricTransportMessage, Error>> + Send + '_ {
+        let begin = self.begin_request(timoutmilliseconds, msg);
+        async move {
+            let (ctx_wapper, rx) = begin?;
+            rx.await.unwrap();
+            let reply = unsafe { self.c.EndRequest(&ctx_wapper.get()) }?;
+            Ok(reply)
+        }
+    }
+
+    // one-way msg, the server does not reply
+    pub fn send(&self, msg: &IFabricTransportMessage) -> Result<(), Error> {
+        unsafe { self.c.Send(msg) }
+    }
+
+    fn begin_request(
+        &self,
+        timoutmilliseconds: u32,
+        msg: &IFabricTransportMessage,
+    ) -> Result<(ContextWrapper, Receiver<()>), Error> {
         let (callback, rx) = AwaitableCallback::create();
         let ctx = unsafe { self.c.BeginRequest(msg, timoutmilliseconds, &callback) }?;
-        rx.await.unwrap();
-        let reply = unsafe { self.c.EndRequest(&ctx) }?;
-        Ok(reply)
+        Ok((ContextWrapper::new(ctx), rx))
     }
 
     pub async fn close(&self, timoutmilliseconds: u32) -> Result<(), Error> {
@@ -0,0 +1,138 @@
+// body codecs. The client names its codec in the content_type of the request
+// header and the server rejects requests a service cannot decode.
+
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use tonic::Status;
+
+use crate::server::{encode_proto, parse_proto};
+
+// empty content type is protobuf, sent by clients without codec support
+pub const PROTOBUF: &str = "application/protobuf";
+pub const JSON: &str = "application/json";
+pub const BINCODE: &str = "application/x-bincode";
+
+// content type of the bodies a codec reads and writes
+pub trait Codec: Send + Sync {
+    fn content_type(&self) -> &'static str;
+}
+
+// encodes and decodes bodies of message type T
+pub trait MessageCodec<T>: Codec {
+    fn encode(&self, msg: &T) -> Result<Bytes, Status>;
+    fn decode(&self, buf: &[u8]) -> Result<T, Status>;
+}
+
+// default codec of generated code
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProstCodec;
+
+impl Codec for ProstCodec {
+    fn content_type(&self) -> &'static str {
+        PROTOBUF
+    }
+}
+
+impl<T: prost::Message + Default> MessageCodec<T> for ProstCodec {
+    fn encode(&self, msg: &T) -> Result<Bytes, Status> {
+        encode_proto(msg)
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<T, Status> {
+        parse_proto(buf)
+    }
+}
+
+// for debugging tools. Prost types need serde impls, e.g. from pbjson-build.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        JSON
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> MessageCodec<T> for JsonCodec {
+    fn encode(&self, msg: &T) -> Result<Bytes, Status> {
+        serde_json::to_vec(msg)
+            .map(Bytes::from)
+            .map_err(|e| Status::internal(format!("failed to encode json: {}", e)))
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<T, Status> {
+        serde_json::from_slice(buf)
+            .map_err(|e| Status::invalid_argument(format!("failed to parse json: {}", e)))
+    }
+}
+
+// for rust services with plain serde types instead of protos
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn content_type(&self) -> &'static str {
+        BINCODE
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> MessageCodec<T> for BincodeCodec {
+    fn encode(&self, msg: &T) -> Result<Bytes, Status> {
+        bincode::serialize(msg)
+            .map(Bytes::from)
+            .map_err(|e| Status::internal(format!("failed to encode bincode: {}", e)))
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<T, Status> {
+        bincode::deserialize(buf)
+            .map_err(|e| Status::invalid_argument(format!("failed to parse bincode: {}", e)))
+    }
+}
+
+// whether a service using codec_type can read a body of content_type
+pub fn content_type_matches(codec_type: &str, content_type: &str) -> bool {
+    codec_type == content_type || (content_type.is_empty() && codec_type == PROTOBUF)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use tonic::Code;
+
+    use super::{BincodeCodec, JsonCodec, MessageCodec, ProstCodec, BINCODE, JSON, PROTOBUF};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        label: String,
+    }
+
+    #[test]
+    fn codec_test() {
+        let p = Point {
+            x: 3,
+            label: String::from("a"),
+        };
+        let buf = JsonCodec.encode(&p).unwrap();
+        assert_eq!(&br#"{"x":3,"label":"a"}"#[..], buf);
+        assert_eq!(p, JsonCodec.decode(&buf).unwrap());
+
+        let buf = BincodeCodec.encode(&p).unwrap();
+        assert_eq!(p, BincodeCodec.decode(&buf).unwrap());
+
+        let buf = MessageCodec::<String>::encode(&ProstCodec, &String::from("abc")).unwrap();
+        let s: String = ProstCodec.decode(&buf).unwrap();
+        assert_eq!("abc", s);
+
+        let err = MessageCodec::<Point>::decode(&JsonCodec, b"{").unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+    }
+
+    #[test]
+    fn content_type_test() {
+        assert!(super::content_type_matches(PROTOBUF, ""));
+        assert!(super::content_type_matches(JSON, JSON));
+        assert!(!super::content_type_matches(JSON, ""));
+        assert!(!super::content_type_matches(BINCODE, PROTOBUF));
+    }
+}
@@ -0,0 +1,236 @@
+// body compression, negotiated per call through the encoding fields
+// of the request and reply headers
+
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use tonic::Status;
+
+// bodies below this are sent as is by default
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    // zlib format, same as grpc and http
+    Deflate,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(body)?;
+                enc.finish()
+            }
+            Encoding::Deflate => {
+                let mut enc =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(body)?;
+                enc.finish()
+            }
+            // 0 is the zstd default level
+            Encoding::Zstd => zstd::encode_all(body, 0),
+        }
+    }
+
+    // reads at most max + 1 bytes, so the caller can tell the body is over max
+    fn decompress(&self, body: &[u8], max: usize) -> std::io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(body)),
+            Encoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(body)),
+            Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(body)?),
+        };
+        let mut out = Vec::new();
+        reader
+            .take(max.saturating_add(1) as u64)
+            .read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+// compression settings of a client or server
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    // encoding of the bodies this side sends, none sends them as is
+    pub send: Option<Encoding>,
+    // encodings this side can decode, others are rejected with Unimplemented
+    pub accept: Vec<Encoding>,
+    // smaller bodies are not worth compressing
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            send: None,
+            accept: vec![Encoding::Gzip, Encoding::Deflate, Encoding::Zstd],
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+}
+
+impl CompressionConfig {
+    // send with encoding, accept everything
+    pub fn sending(encoding: Encoding) -> CompressionConfig {
+        CompressionConfig {
+            send: Some(encoding),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn accept_names(&self) -> Vec<String> {
+        self.accept.iter().map(|e| e.name().to_string()).collect()
+    }
+
+    // Compress body if it is large enough and the peer can decode it.
+    // None for peer_accepts means unknown, the peer rejects what it cannot decode.
+    // Returns the body and its encoding, empty if sent as is.
+    pub(crate) fn compress(
+        &self,
+        body: Bytes,
+        peer_accepts: Option<&[String]>,
+    ) -> Result<(Bytes, String), Status> {
+        let encoding = match self.send {
+            Some(encoding) if body.len() >= self.min_size => encoding,
+            _ => return Ok((body, String::new())),
+        };
+        if let Some(accepts) = peer_accepts {
+            if !accepts.iter().any(|a| a == encoding.name()) {
+                return Ok((body, String::new()));
+            }
+        }
+        let compressed = encoding.compress(&body).map_err(|e| {
+            Status::internal(format!(
+                "failed to {} compress body: {}",
+                encoding.name(),
+                e
+            ))
+        })?;
+        Ok((Bytes::from(compressed), encoding.name().to_string()))
+    }
+
+    // Decode a body received with encoding. Bodies that decode to more than
+    // max_size bytes fail with ResourceExhausted, before they are fully inflated.
+    pub(crate) fn decompress(
+        &self,
+        encoding: &str,
+        body: &Bytes,
+        max_size: usize,
+    ) -> Result<Bytes, Status> {
+        if encoding.is_empty() || encoding == "identity" {
+            return Ok(body.clone());
+        }
+        let enc = match Encoding::from_name(encoding) {
+            Some(enc) if self.accept.contains(&enc) => enc,
+            _ => {
+                return Err(Status::unimplemented(format!(
+                    "encoding {} is not supported, accepted: [{}]",
+                    encoding,
+                    self.accept_names().join(", ")
+                )))
+            }
+        };
+        let body = enc.decompress(body, max_size).map_err(|e| {
+            Status::invalid_argument(format!("failed to {} decompress body: {}", encoding, e))
+        })?;
+        if body.len() > max_size {
+            return Err(Status::resource_exhausted(format!(
+                "{} body decodes to over {} bytes",
+                encoding, max_size
+            )));
+        }
+        Ok(Bytes::from(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tonic::Code;
+
+    use super::{CompressionConfig, Encoding};
+
+    const MAX: usize = 64 * 1024;
+
+    #[test]
+    fn round_trip_test() {
+        let body = Bytes::from("fabric rpc ".repeat(1000));
+        for enc in [Encoding::Gzip, Encoding::Deflate, Encoding::Zstd] {
+            let config = CompressionConfig::sending(enc);
+            let (compressed, name) = config.compress(body.clone(), None).unwrap();
+            assert_eq!(enc.name(), name);
+            assert!(compressed.len() < body.len());
+            let decoded = config.decompress(&name, &compressed, MAX).unwrap();
+            assert_eq!(body, decoded);
+        }
+    }
+
+    #[test]
+    fn negotiation_test() {
+        let config = CompressionConfig::sending(Encoding::Gzip);
+
+        // too small
+        let (body, name) = config.compress(Bytes::from(vec![1, 2, 3]), None).unwrap();
+        assert_eq!(vec![1, 2, 3], body);
+        assert!(name.is_empty());
+
+        // peer cannot decode gzip
+        let big = Bytes::from(vec![0; 4096]);
+        let accepts = vec![String::from("zstd")];
+        let (body, name) = config
+            .compress(big.clone(), Some(accepts.as_slice()))
+            .unwrap();
+        assert_eq!(big, body);
+        assert!(name.is_empty());
+
+        let only_zstd = CompressionConfig {
+            accept: vec![Encoding::Zstd],
+            ..Default::default()
+        };
+        let err = only_zstd.decompress("gzip", &body, MAX).unwrap_err();
+        assert_eq!(Code::Unimplemented, err.code());
+        let err = only_zstd.decompress("br", &body, MAX).unwrap_err();
+        assert_eq!(Code::Unimplemented, err.code());
+        let err = only_zstd.decompress("zstd", &body, MAX).unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+    }
+
+    #[test]
+    fn decoded_limit_test() {
+        // 16 MiB of zeros compress to a few KB
+        let bomb = Bytes::from(vec![0; 16 * 1024 * 1024]);
+        for enc in [Encoding::Gzip, Encoding::Deflate, Encoding::Zstd] {
+            let config = CompressionConfig::sending(enc);
+            let (compressed, name) = config.compress(bomb.clone(), None).unwrap();
+            assert!(compressed.len() < MAX);
+            let err = config.decompress(&name, &compressed, MAX).unwrap_err();
+            assert_eq!(Code::ResourceExhausted, err.code(), "{}", name);
+
+            // right at the limit is fine
+            let body = bomb.slice(..MAX);
+            let (compressed, name) = config.compress(body.clone(), None).unwrap();
+            assert_eq!(body, config.decompress(&name, &compressed, MAX).unwrap());
+        }
+    }
+}
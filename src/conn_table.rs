@@ -0,0 +1,243 @@
+// connection bookkeeping of the server transport, the request queue of every
+// connected client by client id. Kept apart from the COM types so the races
+// between transport threads can be model checked with loom:
+//   RUSTFLAGS="--cfg fabric_rpc_loom" cargo test --release --lib conn_table
+// tokio itself does not build with the plain loom cfg, hence our own.
+
+use std::collections::{hash_map::Entry, HashMap};
+
+#[cfg(fabric_rpc_loom)]
+use loom::sync::Mutex;
+#[cfg(not(fabric_rpc_loom))]
+use std::sync::Mutex;
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+// sending end of the request queue of a connection
+pub(crate) trait Queue: Sized {
+    type Item;
+    type Receiver;
+
+    fn bounded(size: usize) -> (Self, Self::Receiver);
+
+    // must not block, it runs on transport threads
+    fn try_send(&self, item: Self::Item) -> Result<(), TrySendError<Self::Item>>;
+}
+
+impl<T> Queue for mpsc::Sender<T> {
+    type Item = T;
+    type Receiver = mpsc::Receiver<T>;
+
+    fn bounded(size: usize) -> (Self, Self::Receiver) {
+        mpsc::channel(size)
+    }
+
+    fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        mpsc::Sender::try_send(self, item)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TableError {
+    // client id is connected already
+    Exists,
+    // client id is not connected
+    Unknown,
+}
+
+#[derive(Debug)]
+pub(crate) enum PushError<T> {
+    // queue is full, the item is handed back to be rejected
+    Full(T),
+    // receiver is gone
+    Closed,
+    Unknown,
+}
+
+pub(crate) struct ConnectionTable<Q> {
+    conns: Mutex<HashMap<String, Q>>,
+    queue_size: usize,
+}
+
+impl<Q: Queue> ConnectionTable<Q> {
+    pub(crate) fn new(queue_size: usize) -> ConnectionTable<Q> {
+        ConnectionTable {
+            conns: Mutex::new(HashMap::new()),
+            queue_size,
+        }
+    }
+
+    // create the request queue of a new connection
+    pub(crate) fn register(&self, id: &str) -> Result<Q::Receiver, TableError> {
+        match self.conns.lock().unwrap().entry(id.to_string()) {
+            Entry::Occupied(_) => Err(TableError::Exists),
+            Entry::Vacant(v) => {
+                let (tx, rx) = Q::bounded(self.queue_size);
+                v.insert(tx);
+                Ok(rx)
+            }
+        }
+    }
+
+    // dropping the sender ends the connection once its queue is drained
+    pub(crate) fn remove(&self, id: &str) -> Result<(), TableError> {
+        match self.conns.lock().unwrap().remove(id) {
+            Some(_) => Ok(()),
+            None => Err(TableError::Unknown),
+        }
+    }
+
+    pub(crate) fn push(&self, id: &str, item: Q::Item) -> Result<(), PushError<Q::Item>> {
+        let conns = self.conns.lock().unwrap();
+        let tx = conns.get(id).ok_or(PushError::Unknown)?;
+        tx.try_send(item).map_err(|e| match e {
+            TrySendError::Full(item) => PushError::Full(item),
+            TrySendError::Closed(_) => PushError::Closed,
+        })
+    }
+
+    // f runs under the lock, so callers see the counts in order
+    pub(crate) fn with_len<R>(&self, f: impl FnOnce(usize) -> R) -> R {
+        f(self.conns.lock().unwrap().len())
+    }
+}
+
+#[cfg(all(test, fabric_rpc_loom))]
+mod loom_test {
+    use std::collections::VecDeque;
+
+    use loom::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+    use tokio::sync::mpsc::error::TrySendError;
+
+    use super::{ConnectionTable, PushError, Queue, TableError};
+
+    // bounded queue on loom primitives, loom does not see into tokio's
+    struct TestQueue {
+        state: Arc<Mutex<State>>,
+        size: usize,
+    }
+
+    #[derive(Default)]
+    struct State {
+        items: VecDeque<u32>,
+        // sender dropped
+        closed: bool,
+    }
+
+    struct TestReceiver {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl Queue for TestQueue {
+        type Item = u32;
+        type Receiver = TestReceiver;
+
+        fn bounded(size: usize) -> (Self, Self::Receiver) {
+            let state = Arc::new(Mutex::new(State::default()));
+            let rx = TestReceiver {
+                state: state.clone(),
+            };
+            (TestQueue { state, size }, rx)
+        }
+
+        fn try_send(&self, item: u32) -> Result<(), TrySendError<u32>> {
+            let mut state = self.state.lock().unwrap();
+            if state.items.len() == self.size {
+                return Err(TrySendError::Full(item));
+            }
+            state.items.push_back(item);
+            Ok(())
+        }
+    }
+
+    impl Drop for TestQueue {
+        fn drop(&mut self) {
+            self.state.lock().unwrap().closed = true;
+        }
+    }
+
+    impl TestReceiver {
+        // queued items and whether the queue is closed
+        fn drain(&self) -> (Vec<u32>, bool) {
+            let mut state = self.state.lock().unwrap();
+            (state.items.drain(..).collect(), state.closed)
+        }
+    }
+
+    // the transport pushes to a client while two threads disconnect it
+    #[test]
+    fn push_disconnect_test() {
+        loom::model(|| {
+            let table = Arc::new(ConnectionTable::<TestQueue>::new(1));
+            let rx = table.register("a").unwrap();
+
+            let pusher = {
+                let table = table.clone();
+                thread::spawn(move || {
+                    let mut pushed = Vec::new();
+                    for item in [1, 2] {
+                        match table.push("a", item) {
+                            Ok(()) => pushed.push(item),
+                            // 2 finds the queue full with 1 in it
+                            Err(PushError::Full(i)) => assert_eq!(2, i),
+                            Err(PushError::Unknown) => {}
+                            Err(PushError::Closed) => panic!("receiver is alive"),
+                        }
+                    }
+                    let removed = table.remove("a").is_ok();
+                    (pushed, removed)
+                })
+            };
+            let remover = {
+                let table = table.clone();
+                thread::spawn(move || table.remove("a").is_ok())
+            };
+            let (pushed, removed) = pusher.join().unwrap();
+            let removed_other = remover.join().unwrap();
+
+            // removed exactly once, everything accepted is delivered
+            assert!(removed ^ removed_other);
+            let (delivered, closed) = rx.drain();
+            assert_eq!(pushed, delivered);
+            assert!(closed);
+            assert!(matches!(table.push("a", 3), Err(PushError::Unknown)));
+            assert_eq!(0, table.with_len(|n| n));
+        });
+    }
+
+    // a client reconnects while its old connection is removed
+    #[test]
+    fn reconnect_test() {
+        loom::model(|| {
+            let table = Arc::new(ConnectionTable::<TestQueue>::new(1));
+            let old = table.register("a").unwrap();
+
+            let remover = {
+                let table = table.clone();
+                thread::spawn(move || table.remove("a"))
+            };
+            let connector = {
+                let table = table.clone();
+                thread::spawn(move || table.register("a"))
+            };
+            assert_eq!(Ok(()), remover.join().unwrap());
+            match connector.join().unwrap() {
+                // after the remove, the new connection stays registered
+                Ok(new) => {
+                    table.push("a", 1).unwrap();
+                    assert_eq!((vec![1], false), new.drain());
+                    assert_eq!(1, table.with_len(|n| n));
+                }
+                // before it, the old one still held the id
+                Err(e) => {
+                    assert_eq!(TableError::Exists, e);
+                    assert_eq!(0, table.with_len(|n| n));
+                }
+            }
+            assert!(old.drain().1);
+        });
+    }
+}
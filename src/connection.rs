@@ -0,0 +1,106 @@
+// connection lifecycle events and per-connection state
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+// typed values attached to one connection, shared by all its requests.
+// Store Arc<Mutex<T>> for state that handlers update.
+#[derive(Clone, Default)]
+pub struct ConnectionState {
+    values: Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+
+impl ConnectionState {
+    // returns the previous value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&self, val: T) -> Option<T> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.values
+            .lock()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref::<T>())
+            .cloned()
+    }
+
+    pub fn get_or_insert_with<T: Clone + Send + Sync + 'static>(&self, f: impl FnOnce() -> T) -> T {
+        let mut values = self.values.lock().unwrap();
+        let val = values
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()));
+        val.downcast_ref::<T>().unwrap().clone()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.values
+            .lock()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+}
+
+impl fmt::Debug for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionState")
+            .field("len", &self.values.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    Connected {
+        client_id: String,
+        connection_id: u64,
+    },
+    // sent after the last request of the connection finished,
+    // or when the server shuts down. state is what handlers left behind.
+    Disconnected {
+        client_id: String,
+        connection_id: u64,
+        state: ConnectionState,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::ConnectionState;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Session(String);
+
+    #[test]
+    fn state_test() {
+        let state = ConnectionState::default();
+        assert!(state.get::<Session>().is_none());
+
+        assert!(state.insert(Session(String::from("a"))).is_none());
+        let prev = state.insert(Session(String::from("b")));
+        assert_eq!(Some(Session(String::from("a"))), prev);
+
+        // clones share the values
+        let state2 = state.clone();
+        assert_eq!(Some(Session(String::from("b"))), state2.get::<Session>());
+
+        let counter = state.get_or_insert_with(|| Arc::new(Mutex::new(0)));
+        *counter.lock().unwrap() += 1;
+        let counter = state2.get_or_insert_with(|| Arc::new(Mutex::new(0)));
+        assert_eq!(1, *counter.lock().unwrap());
+
+        assert_eq!(Some(Session(String::from("b"))), state.remove::<Session>());
+        assert!(state2.get::<Session>().is_none());
+    }
+}
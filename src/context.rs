@@ -0,0 +1,204 @@
+// per request info for handlers and server interceptors
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::{connection::ConnectionState, trace::TraceContext};
+
+tokio::task_local! {
+    static CURRENT: RequestContext;
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    url: String,
+    content_type: String,
+    client_id: String,
+    connection_id: u64,
+    remote_address: Option<String>,
+    received_at: Instant,
+    deadline: Option<Instant>,
+    one_way: bool,
+    metadata: HashMap<String, String>,
+    connection_state: ConnectionState,
+    trace: Option<TraceContext>,
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        RequestContext {
+            url: String::new(),
+            content_type: String::new(),
+            client_id: String::new(),
+            connection_id: 0,
+            remote_address: None,
+            received_at: Instant::now(),
+            deadline: None,
+            one_way: false,
+            metadata: HashMap::new(),
+            connection_state: ConnectionState::default(),
+            trace: None,
+        }
+    }
+}
+
+impl RequestContext {
+    pub(crate) fn new(
+        client_id: String,
+        connection_id: u64,
+        connection_state: ConnectionState,
+        received_at: Instant,
+        timeout_milliseconds: u32,
+        one_way: bool,
+    ) -> RequestContext {
+        // u32::MAX is INFINITE in fabric
+        let deadline = if timeout_milliseconds == u32::MAX {
+            None
+        } else {
+            Some(received_at + Duration::from_millis(timeout_milliseconds as u64))
+        };
+        RequestContext {
+            client_id,
+            connection_id,
+            received_at,
+            deadline,
+            one_way,
+            connection_state,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_request(
+        &mut self,
+        url: String,
+        content_type: String,
+        metadata: HashMap<String, String>,
+    ) {
+        self.url = url;
+        self.content_type = content_type;
+        self.metadata = metadata;
+    }
+
+    pub(crate) fn set_trace_context(&mut self, trace: TraceContext) {
+        self.trace = Some(trace);
+    }
+
+    // context of the request being handled by the current task.
+    // None outside of a handler, or in tasks spawned by the handler.
+    pub fn current() -> Option<RequestContext> {
+        CURRENT.try_with(|ctx| ctx.clone()).ok()
+    }
+
+    // run f with self as the current context, also useful to test handlers
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // codec the client encoded the body with, empty is protobuf
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    // id fabric transport assigned to the client
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    // unique per accepted connection for the lifetime of the server
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    // fabric transport does not expose the peer address, so this is none for now
+    pub fn remote_address(&self) -> Option<&str> {
+        self.remote_address.as_deref()
+    }
+
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    // when the client stops waiting for the reply
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    // the client does not wait for a reply
+    pub fn is_one_way(&self) -> bool {
+        self.one_way
+    }
+
+    // key values sent by client interceptors in the request header
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    // server interceptors can attach values for handlers
+    pub fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.metadata
+    }
+
+    // values kept for the lifetime of the connection, e.g. a login session
+    pub fn connection_state(&self) -> &ConnectionState {
+        &self.connection_state
+    }
+
+    // span of the server call. Calls made by the handler become its children.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RequestContext;
+    use crate::connection::ConnectionState;
+
+    #[tokio::test]
+    async fn context_test() {
+        assert!(RequestContext::current().is_none());
+
+        let now = Instant::now();
+        let mut ctx = RequestContext::new(
+            String::from("client1"),
+            3,
+            ConnectionState::default(),
+            now,
+            1000,
+            false,
+        );
+        ctx.metadata_mut()
+            .insert(String::from("user"), String::from("alice"));
+        assert_eq!(Some(now + Duration::from_secs(1)), ctx.deadline());
+
+        let user = ctx
+            .scope(async {
+                let cur = RequestContext::current().unwrap();
+                assert_eq!("client1", cur.client_id());
+                assert_eq!(3, cur.connection_id());
+                cur.metadata().get("user").cloned()
+            })
+            .await;
+        assert_eq!(Some(String::from("alice")), user);
+
+        let ctx = RequestContext::new(
+            String::new(),
+            0,
+            ConnectionState::default(),
+            now,
+            u32::MAX,
+            true,
+        );
+        assert!(ctx.deadline().is_none());
+        assert!(ctx.is_one_way());
+    }
+}
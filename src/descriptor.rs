@@ -0,0 +1,79 @@
+// static service descriptors emitted by fabric-rpc-build
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamingKind {
+    Unary,
+    ClientStreaming,
+    ServerStreaming,
+    BidiStreaming,
+}
+
+impl StreamingKind {
+    // const so generated descriptors can call it in statics
+    pub const fn new(client_streaming: bool, server_streaming: bool) -> StreamingKind {
+        match (client_streaming, server_streaming) {
+            (false, false) => StreamingKind::Unary,
+            (true, false) => StreamingKind::ClientStreaming,
+            (false, true) => StreamingKind::ServerStreaming,
+            (true, true) => StreamingKind::BidiStreaming,
+        }
+    }
+}
+
+// mirrors google.protobuf.MethodOptions.IdempotencyLevel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idempotency {
+    Unknown,
+    NoSideEffects,
+    Idempotent,
+}
+
+#[derive(Debug)]
+pub struct MethodDescriptor {
+    // method name as in the proto file
+    pub name: &'static str,
+    pub url: &'static str,
+    // full proto type names without leading dot
+    pub input_type: &'static str,
+    pub output_type: &'static str,
+    pub streaming: StreamingKind,
+    pub idempotency: Idempotency,
+}
+
+#[derive(Debug)]
+pub struct ServiceDescriptor {
+    // full service name including package
+    pub name: &'static str,
+    pub methods: &'static [MethodDescriptor],
+}
+
+impl ServiceDescriptor {
+    pub fn method_by_url(&self, url: &str) -> Option<&'static MethodDescriptor> {
+        self.methods.iter().find(|m| m.url == url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Idempotency, MethodDescriptor, ServiceDescriptor, StreamingKind};
+
+    static DESC: ServiceDescriptor = ServiceDescriptor {
+        name: "helloworld.Greeter",
+        methods: &[MethodDescriptor {
+            name: "SayHello",
+            url: "/helloworld.Greeter/say_hello",
+            input_type: "helloworld.HelloRequest",
+            output_type: "helloworld.HelloReply",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::Unknown,
+        }],
+    };
+
+    #[test]
+    fn descriptor_test() {
+        let m = DESC.method_by_url("/helloworld.Greeter/say_hello").unwrap();
+        assert_eq!(m.name, "SayHello");
+        assert!(DESC.method_by_url("/helloworld.Greeter/other").is_none());
+        assert_eq!(StreamingKind::new(true, true), StreamingKind::BidiStreaming);
+    }
+}
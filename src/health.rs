@@ -0,0 +1,414 @@
+// health checking service, modeled on grpc.health.v1.
+// The app flips serving status with HealthReporter,
+// and Server marks everything NOT_SERVING on shutdown.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::watch;
+use tonic::{async_trait, Status};
+
+use crate::{
+    client::{unary, Channel},
+    descriptor::{Idempotency, MethodDescriptor, ServiceDescriptor, StreamingKind},
+    server::{encode_proto, parse_proto, Service},
+};
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/fabricrpc.health.v1.rs"));
+}
+
+pub use proto::health_check_response::ServingStatus;
+use proto::{HealthCheckRequest, HealthCheckResponse, HealthWatchRequest};
+
+const SERVICE_NAME: &str = "fabricrpc.health.v1.Health";
+const CHECK_URL: &str = "/fabricrpc.health.v1.Health/check";
+const WATCH_URL: &str = "/fabricrpc.health.v1.Health/watch";
+
+pub static SERVICE_DESCRIPTOR: ServiceDescriptor = ServiceDescriptor {
+    name: SERVICE_NAME,
+    methods: &[
+        MethodDescriptor {
+            name: "Check",
+            url: CHECK_URL,
+            input_type: "fabricrpc.health.v1.HealthCheckRequest",
+            output_type: "fabricrpc.health.v1.HealthCheckResponse",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::NoSideEffects,
+        },
+        MethodDescriptor {
+            name: "Watch",
+            url: WATCH_URL,
+            input_type: "fabricrpc.health.v1.HealthWatchRequest",
+            output_type: "fabricrpc.health.v1.HealthCheckResponse",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::NoSideEffects,
+        },
+    ],
+};
+
+// services watched but never set are at most this many, more watches of
+// unknown services are rejected
+const MAX_UNKNOWN_WATCHED: usize = 1024;
+
+#[derive(Default)]
+struct Statuses {
+    // set by the app
+    services: HashMap<String, watch::Sender<ServingStatus>>,
+    // only watched so far, SERVICE_UNKNOWN until the app sets them.
+    // Removed with their last watcher.
+    unknown: HashMap<String, watch::Sender<ServingStatus>>,
+}
+
+type StatusMap = Arc<Mutex<Statuses>>;
+
+// handle for the app to set serving status per service name.
+// Empty service name is the overall server status.
+#[derive(Clone)]
+pub struct HealthReporter {
+    statuses: StatusMap,
+}
+
+impl HealthReporter {
+    fn new() -> HealthReporter {
+        let reporter = HealthReporter {
+            statuses: Arc::new(Mutex::new(Statuses::default())),
+        };
+        reporter.set_service_status("", ServingStatus::Serving);
+        reporter
+    }
+
+    pub fn set_service_status(&self, service_name: &str, status: ServingStatus) {
+        let mut statuses = self.statuses.lock().unwrap();
+        match statuses.services.get(service_name) {
+            Some(tx) => {
+                tx.send_replace(status);
+            }
+            None => {
+                // watchers of the unknown service see it set
+                let tx = match statuses.unknown.remove(service_name) {
+                    Some(tx) => {
+                        tx.send_replace(status);
+                        tx
+                    }
+                    None => watch::channel(status).0,
+                };
+                statuses.services.insert(service_name.to_string(), tx);
+            }
+        }
+    }
+
+    pub fn set_serving(&self, service_name: &str) {
+        self.set_service_status(service_name, ServingStatus::Serving);
+    }
+
+    pub fn set_not_serving(&self, service_name: &str) {
+        self.set_service_status(service_name, ServingStatus::NotServing);
+    }
+
+    // mark all services including the server as not serving
+    pub fn set_all_not_serving(&self) {
+        let statuses = self.statuses.lock().unwrap();
+        for tx in statuses.services.values() {
+            tx.send_replace(ServingStatus::NotServing);
+        }
+    }
+
+    pub fn get_service_status(&self, service_name: &str) -> Option<ServingStatus> {
+        let statuses = self.statuses.lock().unwrap();
+        statuses.services.get(service_name).map(|tx| *tx.borrow())
+    }
+
+    fn subscribe(&self, service_name: &str) -> Result<Watcher, Status> {
+        let mut statuses = self.statuses.lock().unwrap();
+        let rx = match statuses.services.get(service_name) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let full = statuses.unknown.len() >= MAX_UNKNOWN_WATCHED;
+                match statuses.unknown.get(service_name) {
+                    Some(tx) => tx.subscribe(),
+                    None if full => {
+                        return Err(Status::resource_exhausted(
+                            "too many unknown services watched",
+                        ))
+                    }
+                    None => {
+                        let (tx, rx) = watch::channel(ServingStatus::ServiceUnknown);
+                        statuses.unknown.insert(service_name.to_string(), tx);
+                        rx
+                    }
+                }
+            }
+        };
+        Ok(Watcher {
+            rx: Some(rx),
+            service_name: service_name.to_string(),
+            statuses: self.statuses.clone(),
+        })
+    }
+}
+
+// watch of one service, drops the unknown entry with the last watcher
+struct Watcher {
+    rx: Option<watch::Receiver<ServingStatus>>,
+    service_name: String,
+    statuses: StatusMap,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // under the lock, so no new watcher subscribes in between
+        let mut statuses = self.statuses.lock().unwrap();
+        drop(self.rx.take());
+        let unwatched = statuses
+            .unknown
+            .get(&self.service_name)
+            .is_some_and(|tx| tx.receiver_count() == 0);
+        if unwatched {
+            statuses.unknown.remove(&self.service_name);
+        }
+    }
+}
+
+// create the reporter and the service to register on Server
+pub fn health_reporter() -> (HealthReporter, HealthService) {
+    let reporter = HealthReporter::new();
+    let svc = HealthService {
+        reporter: reporter.clone(),
+    };
+    (reporter, svc)
+}
+
+pub struct HealthService {
+    reporter: HealthReporter,
+}
+
+impl HealthService {
+    fn check(&self, req: HealthCheckRequest) -> Result<HealthCheckResponse, Status> {
+        match self.reporter.get_service_status(&req.service) {
+            Some(status) => Ok(HealthCheckResponse {
+                status: status as i32,
+            }),
+            None => Err(Status::not_found(format!(
+                "service not found: {}",
+                req.service
+            ))),
+        }
+    }
+
+    // returns once the status differs from last_status
+    async fn watch(&self, req: HealthWatchRequest) -> Result<HealthCheckResponse, Status> {
+        let mut watcher = self.reporter.subscribe(&req.service)?;
+        let rx = watcher.rx.as_mut().unwrap();
+        loop {
+            let status = *rx.borrow_and_update();
+            if status as i32 != req.last_status {
+                return Ok(HealthCheckResponse {
+                    status: status as i32,
+                });
+            }
+            if rx.changed().await.is_err() {
+                // reporter dropped, nothing will change any more
+                return Ok(HealthCheckResponse {
+                    status: status as i32,
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Service for HealthService {
+    fn name(&self) -> String {
+        String::from(SERVICE_NAME)
+    }
+
+    fn descriptor(&self) -> Option<&'static ServiceDescriptor> {
+        Some(&SERVICE_DESCRIPTOR)
+    }
+
+    fn on_shutdown(&self) {
+        self.reporter.set_all_not_serving();
+    }
+
+    async fn handle_request(
+        &self,
+        url: String,
+        request: Bytes,
+    ) -> std::result::Result<Bytes, tonic::Status> {
+        match url.as_str() {
+            CHECK_URL => {
+                let req = parse_proto(&request)?;
+                encode_proto(&self.check(req)?)
+            }
+            WATCH_URL => {
+                let req = parse_proto(&request)?;
+                encode_proto(&self.watch(req).await?)
+            }
+            _ => Err(tonic::Status::unimplemented("url not found")),
+        }
+    }
+}
+
+// client for the health service over any channel
+pub struct HealthClient<C> {
+    c: C,
+}
+
+impl<C: Channel> HealthClient<C> {
+    pub fn new(channel: C) -> HealthClient<C> {
+        HealthClient { c: channel }
+    }
+
+    pub async fn check(
+        &self,
+        timoutmilliseconds: u32,
+        request: HealthCheckRequest,
+    ) -> Result<HealthCheckResponse, Status> {
+        let url = String::from(CHECK_URL);
+        unary(&self.c, url, &request, timoutmilliseconds).await
+    }
+
+    pub async fn watch(
+        &self,
+        timoutmilliseconds: u32,
+        request: HealthWatchRequest,
+    ) -> Result<HealthCheckResponse, Status> {
+        let url = String::from(WATCH_URL);
+        unary(&self.c, url, &request, timoutmilliseconds).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prost::Message;
+    use tonic::Code;
+
+    use super::{
+        health_reporter,
+        proto::{HealthCheckRequest, HealthCheckResponse, HealthWatchRequest},
+        ServingStatus,
+    };
+    use crate::server::{encode_proto, Service};
+
+    async fn call(
+        svc: &impl Service,
+        url: &str,
+        req: &impl Message,
+    ) -> Result<HealthCheckResponse, tonic::Status> {
+        let body = svc
+            .handle_request(url.to_string(), encode_proto(req)?)
+            .await?;
+        Ok(HealthCheckResponse::decode(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn health_test() {
+        let (reporter, svc) = health_reporter();
+        let check_url = "/fabricrpc.health.v1.Health/check";
+        let watch_url = "/fabricrpc.health.v1.Health/watch";
+
+        // server itself is serving by default
+        let resp = call(&svc, check_url, &HealthCheckRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(ServingStatus::Serving as i32, resp.status);
+
+        let req = HealthCheckRequest {
+            service: String::from("todolist.Todo"),
+        };
+        let err = call(&svc, check_url, &req).await.unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+
+        reporter.set_serving("todolist.Todo");
+        let resp = call(&svc, check_url, &req).await.unwrap();
+        assert_eq!(ServingStatus::Serving as i32, resp.status);
+
+        // watch returns once status changes
+        let watch_req = HealthWatchRequest {
+            service: String::from("todolist.Todo"),
+            last_status: ServingStatus::Serving as i32,
+        };
+        let reporter2 = reporter.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            reporter2.set_not_serving("todolist.Todo");
+        });
+        let resp = call(&svc, watch_url, &watch_req).await.unwrap();
+        assert_eq!(ServingStatus::NotServing as i32, resp.status);
+
+        let watch_req = HealthWatchRequest {
+            service: String::from("not.Exist"),
+            last_status: ServingStatus::Unknown as i32,
+        };
+        let resp = call(&svc, watch_url, &watch_req).await.unwrap();
+        assert_eq!(ServingStatus::ServiceUnknown as i32, resp.status);
+
+        // watching does not register the service
+        let req = HealthCheckRequest {
+            service: String::from("not.Exist"),
+        };
+        let err = call(&svc, check_url, &req).await.unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+
+        // a dropped watch leaves nothing behind
+        let watch_req = HealthWatchRequest {
+            service: String::from("not.Exist"),
+            last_status: ServingStatus::ServiceUnknown as i32,
+        };
+        let watch = call(&svc, watch_url, &watch_req);
+        assert!(tokio::time::timeout(Duration::from_millis(10), watch)
+            .await
+            .is_err());
+        assert!(reporter.statuses.lock().unwrap().unknown.is_empty());
+
+        // shutdown does not report services that are only watched
+        {
+            let watch = call(&svc, watch_url, &watch_req);
+            tokio::pin!(watch);
+            assert!(tokio::time::timeout(Duration::from_millis(10), &mut watch)
+                .await
+                .is_err());
+            svc.on_shutdown();
+            assert_eq!(None, reporter.get_service_status("not.Exist"));
+            assert!(tokio::time::timeout(Duration::from_millis(10), &mut watch)
+                .await
+                .is_err());
+        }
+        assert!(reporter.statuses.lock().unwrap().unknown.is_empty());
+
+        // the watch returns once the unknown service is set
+        let watch_req = HealthWatchRequest {
+            service: String::from("not.Exist"),
+            last_status: ServingStatus::ServiceUnknown as i32,
+        };
+        let reporter2 = reporter.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            reporter2.set_serving("not.Exist");
+        });
+        let resp = call(&svc, watch_url, &watch_req).await.unwrap();
+        assert_eq!(ServingStatus::Serving as i32, resp.status);
+        assert_eq!(
+            Some(ServingStatus::Serving),
+            reporter.get_service_status("not.Exist")
+        );
+
+        // shutdown flips everything
+        reporter.set_serving("todolist.Todo");
+        svc.on_shutdown();
+        assert_eq!(
+            Some(ServingStatus::NotServing),
+            reporter.get_service_status("")
+        );
+        assert_eq!(
+            Some(ServingStatus::NotServing),
+            reporter.get_service_status("todolist.Todo")
+        );
+    }
+}
@@ -1,11 +1,27 @@
 pub mod client_tr;
+mod conn_table;
 pub mod server_tr;
 pub mod shared_tr;
 pub mod sys;
 
+pub mod buffer;
+pub mod chunk;
 pub mod client;
+pub mod codec;
+pub mod compression;
+pub mod connection;
+pub mod context;
+pub mod descriptor;
 pub mod fabricrpc_header;
+pub mod health;
+pub mod metrics;
+pub mod middleware;
+pub mod mock;
+pub mod notify;
+pub mod protocol;
+pub mod reflection;
 pub mod server;
+pub mod trace;
 
 // private tests
 #[cfg(test)]
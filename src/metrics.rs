@@ -0,0 +1,147 @@
+// metrics hook for rpc calls on client and server.
+// Plug a recorder into Client2::with_metrics or Server::metrics and forward
+// to the metrics backend of choice. MemoryRecorder keeps everything in memory.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tonic::Code;
+
+// which end of the call is recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+// result of one call
+#[derive(Debug, Clone)]
+pub struct CallOutcome {
+    pub code: Code,
+    // on the server this includes the time queued
+    pub latency: Duration,
+    // encoded body sizes, reply is 0 on error
+    pub request_size: usize,
+    pub reply_size: usize,
+}
+
+// Methods are called inline on the request path, so they must be quick.
+// Urls are like /package.Service/method.
+pub trait MetricsRecorder: Send + Sync {
+    // call was sent or dispatched, it is in flight until finished
+    fn call_started(&self, _side: Side, _url: &str) {}
+
+    // reply was built on the server or decoded on the client
+    fn call_finished(&self, _side: Side, _url: &str, _outcome: &CallOutcome) {}
+
+    // number of transport connections open on the server changed
+    fn connections_changed(&self, _open: usize) {}
+}
+
+// everything recorded for one method
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodMetrics {
+    pub requests: u64,
+    pub in_flight: i64,
+    pub codes: HashMap<Code, u64>,
+    pub latencies: Vec<Duration>,
+    pub request_sizes: Vec<usize>,
+    pub reply_sizes: Vec<usize>,
+}
+
+impl MethodMetrics {
+    pub fn errors(&self) -> u64 {
+        self.codes
+            .iter()
+            .filter(|(code, _)| **code != Code::Ok)
+            .map(|(_, n)| n)
+            .sum()
+    }
+}
+
+// keeps all values in memory, for tests
+#[derive(Debug, Default)]
+pub struct MemoryRecorder {
+    methods: Mutex<HashMap<(Side, String), MethodMetrics>>,
+    connections: AtomicUsize,
+}
+
+impl MemoryRecorder {
+    // empty if nothing was recorded for the method
+    pub fn method(&self, side: Side, url: &str) -> MethodMetrics {
+        self.methods
+            .lock()
+            .unwrap()
+            .get(&(side, url.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl MetricsRecorder for MemoryRecorder {
+    fn call_started(&self, side: Side, url: &str) {
+        let mut methods = self.methods.lock().unwrap();
+        let m = methods.entry((side, url.to_string())).or_default();
+        m.requests += 1;
+        m.in_flight += 1;
+    }
+
+    fn call_finished(&self, side: Side, url: &str, outcome: &CallOutcome) {
+        let mut methods = self.methods.lock().unwrap();
+        let m = methods.entry((side, url.to_string())).or_default();
+        m.in_flight -= 1;
+        *m.codes.entry(outcome.code).or_default() += 1;
+        m.latencies.push(outcome.latency);
+        m.request_sizes.push(outcome.request_size);
+        m.reply_sizes.push(outcome.reply_size);
+    }
+
+    fn connections_changed(&self, open: usize) {
+        self.connections.store(open, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tonic::Code;
+
+    use super::{CallOutcome, MemoryRecorder, MetricsRecorder, Side};
+
+    #[test]
+    fn memory_recorder_test() {
+        let rec = MemoryRecorder::default();
+        let url = "/test.Svc/method";
+        rec.call_started(Side::Client, url);
+        rec.call_started(Side::Client, url);
+        rec.call_finished(
+            Side::Client,
+            url,
+            &CallOutcome {
+                code: Code::NotFound,
+                latency: Duration::from_millis(3),
+                request_size: 5,
+                reply_size: 0,
+            },
+        );
+
+        let m = rec.method(Side::Client, url);
+        assert_eq!(2, m.requests);
+        assert_eq!(1, m.in_flight);
+        assert_eq!(1, m.errors());
+        assert_eq!(vec![Duration::from_millis(3)], m.latencies);
+        assert_eq!(vec![5], m.request_sizes);
+        assert_eq!(0, rec.method(Side::Server, url).requests);
+    }
+}
@@ -0,0 +1,214 @@
+// tower integration. Client2 is a tower service, and tower layers can wrap
+// server dispatch, so timeouts, limits etc. compose with fabric-rpc.
+
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use tonic::{async_trait, Status};
+use tower::{
+    buffer::Buffer, load_shed::error::Overloaded, timeout::error::Elapsed, util::BoxService,
+    BoxError, Service, ServiceExt,
+};
+
+use crate::{client::Channel, fabricrpc_header::RequestHeader};
+
+// encoded request going through a tower stack, replied with the encoded body
+#[derive(Debug, Clone, Default)]
+pub struct FabricRequest {
+    pub header: RequestHeader,
+    pub body: Bytes,
+    pub timeout_milliseconds: u32,
+    // sent without waiting for a reply, replied with an empty body
+    pub one_way: bool,
+}
+
+impl FabricRequest {
+    pub fn new(url: String, body: Bytes, timeout_milliseconds: u32) -> FabricRequest {
+        FabricRequest {
+            header: RequestHeader {
+                url,
+                ..Default::default()
+            },
+            body,
+            timeout_milliseconds,
+            one_way: false,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.header.url
+    }
+}
+
+// innermost service of the server stack, routes to the registered services.
+// Layers added with Server::layer wrap it, they need not be Clone since
+// the stack is driven behind a Buffer.
+pub type DispatchService = BoxService<FabricRequest, Bytes, BoxError>;
+
+// errors from tower layers become the closest grpc status
+pub fn status_from_error(err: BoxError) -> Status {
+    if let Some(status) = err.downcast_ref::<Status>() {
+        return status.clone();
+    }
+    if err.is::<Elapsed>() {
+        return Status::deadline_exceeded(err.to_string());
+    }
+    if err.is::<Overloaded>() {
+        return Status::resource_exhausted(err.to_string());
+    }
+    Status::unknown(err.to_string())
+}
+
+// requests waiting for the stack of a channel to be ready
+const CHANNEL_BUFFER_SIZE: usize = 1024;
+
+// Channel over a tower service, e.g. Client2 wrapped in layers,
+// so generated clients and unary calls go through the stack.
+// Calls share the one stack, so layers like rate limits see all of them.
+pub struct ServiceChannel<S>
+where
+    S: Service<FabricRequest>,
+{
+    inner: Mutex<Buffer<S, FabricRequest>>,
+}
+
+impl<S> ServiceChannel<S>
+where
+    S: Service<FabricRequest, Response = Bytes> + Send + 'static,
+    S::Error: Into<BoxError> + Send + Sync,
+    S::Future: Send,
+{
+    // spawns the task driving the stack, call within a tokio runtime
+    pub fn new(inner: S) -> ServiceChannel<S> {
+        ServiceChannel {
+            inner: Mutex::new(Buffer::new(inner, CHANNEL_BUFFER_SIZE)),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> Channel for ServiceChannel<S>
+where
+    S: Service<FabricRequest, Response = Bytes> + Send + 'static,
+    S::Error: Into<BoxError> + Send + Sync,
+    S::Future: Send,
+{
+    async fn call(
+        &self,
+        header: RequestHeader,
+        body: Bytes,
+        timoutmilliseconds: u32,
+    ) -> Result<Bytes, Status> {
+        // handle to the shared stack, not a copy of it
+        let svc = self.inner.lock().unwrap().clone();
+        let req = FabricRequest {
+            header,
+            body,
+            timeout_milliseconds: timoutmilliseconds,
+            one_way: false,
+        };
+        svc.oneshot(req).await.map_err(status_from_error)
+    }
+
+    // through the stack too, so its layers see one-way msgs
+    async fn send_one_way(&self, header: RequestHeader, body: Bytes) -> Result<(), Status> {
+        let svc = self.inner.lock().unwrap().clone();
+        let req = FabricRequest {
+            header,
+            body,
+            // nothing to wait for
+            timeout_milliseconds: u32::MAX,
+            one_way: true,
+        };
+        svc.oneshot(req)
+            .await
+            .map(|_| ())
+            .map_err(status_from_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tonic::{Code, Status};
+    use tower::{service_fn, timeout::Timeout, BoxError, ServiceBuilder};
+
+    use crate::client::{unary, Channel};
+
+    use super::{status_from_error, FabricRequest, ServiceChannel};
+
+    // echoes the url back as the body
+    fn echo(req: FabricRequest) -> futures::future::Ready<Result<Bytes, Status>> {
+        futures::future::ready(Ok(Bytes::copy_from_slice(req.url().as_bytes())))
+    }
+
+    #[tokio::test]
+    async fn channel_test() {
+        let channel = ServiceChannel::new(service_fn(echo));
+        let req = FabricRequest::new(String::from("/test.Echo/echo"), Bytes::new(), 1000);
+        let body = channel.call(req.header, req.body, 1000).await.unwrap();
+        assert_eq!(&b"/test.Echo/echo"[..], body);
+
+        let slow = service_fn(|_: FabricRequest| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, Status>(Bytes::new())
+        });
+        let channel = ServiceChannel::new(Timeout::new(slow, Duration::from_millis(10)));
+        let err = unary::<_, ()>(&channel, String::from("/test.Echo/echo"), &(), 1000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::DeadlineExceeded, err.code());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_test() {
+        // one call per minute, the rest are shed
+        let channel = ServiceChannel::new(
+            ServiceBuilder::new()
+                .load_shed()
+                .rate_limit(1, Duration::from_secs(60))
+                .service(service_fn(echo)),
+        );
+        let req = FabricRequest::new(String::from("/test.Echo/echo"), Bytes::new(), 1000);
+        channel
+            .call(req.header.clone(), req.body.clone(), 1000)
+            .await
+            .unwrap();
+        let err = channel.call(req.header, req.body, 1000).await.unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+    }
+
+    #[tokio::test]
+    async fn one_way_test() {
+        // one msg per minute, the rest are shed
+        let channel = ServiceChannel::new(
+            ServiceBuilder::new()
+                .load_shed()
+                .rate_limit(1, Duration::from_secs(60))
+                .service(service_fn(|req: FabricRequest| async move {
+                    assert!(req.one_way);
+                    Ok::<_, Status>(Bytes::new())
+                })),
+        );
+        let req = FabricRequest::new(String::from("/test.Echo/event"), Bytes::new(), 1000);
+        channel
+            .send_one_way(req.header.clone(), req.body.clone())
+            .await
+            .unwrap();
+        let err = channel
+            .send_one_way(req.header, req.body)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+    }
+
+    #[test]
+    fn status_test() {
+        let err: BoxError = Box::new(Status::not_found("gone"));
+        assert_eq!(Code::NotFound, status_from_error(err).code());
+        let err: BoxError = "boom".into();
+        assert_eq!(Code::Unknown, status_from_error(err).code());
+    }
+}
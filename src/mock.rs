@@ -0,0 +1,115 @@
+// mock support used by generated mock clients
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use tonic::Status;
+
+type Handler<Req, Resp> = Box<dyn FnMut(Req) -> Result<Resp, Status> + Send>;
+
+// Expectations for one rpc method.
+// Queued responses are returned in order, one per call.
+// When the queue is empty the handler is used if set,
+// otherwise the call fails with Unimplemented.
+pub struct MockMethod<Req, Resp> {
+    name: &'static str,
+    responses: Mutex<VecDeque<Result<Resp, Status>>>,
+    handler: Mutex<Option<Handler<Req, Resp>>>,
+    requests: Mutex<Vec<Req>>,
+}
+
+impl<Req, Resp> MockMethod<Req, Resp> {
+    pub fn new(name: &'static str) -> MockMethod<Req, Resp> {
+        MockMethod {
+            name,
+            responses: Mutex::new(VecDeque::new()),
+            handler: Mutex::new(None),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    // queue a canned response for the next call
+    pub fn returning(&self, resp: Resp) -> &Self {
+        self.responses.lock().unwrap().push_back(Ok(resp));
+        self
+    }
+
+    // queue a canned error for the next call
+    pub fn returning_err(&self, status: Status) -> &Self {
+        self.responses.lock().unwrap().push_back(Err(status));
+        self
+    }
+
+    // handler used when no canned response is queued
+    pub fn with_handler<F>(&self, f: F) -> &Self
+    where
+        F: FnMut(Req) -> Result<Resp, Status> + Send + 'static,
+    {
+        *self.handler.lock().unwrap() = Some(Box::new(f));
+        self
+    }
+
+    pub fn call_count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    // drop all expectations and recorded requests
+    pub fn reset(&self) {
+        self.responses.lock().unwrap().clear();
+        *self.handler.lock().unwrap() = None;
+        self.requests.lock().unwrap().clear();
+    }
+}
+
+impl<Req: Clone, Resp> MockMethod<Req, Resp> {
+    // requests received so far, in call order
+    pub fn requests(&self) -> Vec<Req> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn call(&self, req: Req) -> Result<Resp, Status> {
+        self.requests.lock().unwrap().push(req.clone());
+        if let Some(resp) = self.responses.lock().unwrap().pop_front() {
+            return resp;
+        }
+        match self.handler.lock().unwrap().as_mut() {
+            Some(h) => h(req),
+            None => Err(Status::unimplemented(format!(
+                "no expectation set for {}",
+                self.name
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{Code, Status};
+
+    use super::MockMethod;
+
+    #[test]
+    fn mock_method_test() {
+        let m = MockMethod::<String, String>::new("test.Svc/method");
+        assert_eq!(
+            m.call("a".to_string()).unwrap_err().code(),
+            Code::Unimplemented
+        );
+
+        m.returning("first".to_string())
+            .returning_err(Status::not_found("second"));
+        m.with_handler(|req| Ok(format!("handled: {}", req)));
+
+        assert_eq!(m.call("b".to_string()).unwrap(), "first");
+        assert_eq!(m.call("c".to_string()).unwrap_err().code(), Code::NotFound);
+        assert_eq!(m.call("d".to_string()).unwrap(), "handled: d");
+        assert_eq!(m.call_count(), 4);
+        assert_eq!(m.requests(), vec!["a", "b", "c", "d"]);
+
+        m.reset();
+        assert_eq!(m.call_count(), 0);
+        assert_eq!(
+            m.call("e".to_string()).unwrap_err().code(),
+            Code::Unimplemented
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// server push of one-way notifications to connected clients.
+// Clients receive them with Client2::subscribe.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use fabric_base::FabricCommon::FabricTransport::IFabricTransportMessage;
+use prost::Message;
+use tonic::Status;
+
+use crate::{fabricrpc_header::RequestHeader, server::encode_proto, server_tr::ClientConnection};
+
+// handle to the connections of a running Server, keyed by client id.
+// Each entry keeps the id of its connection, a reconnecting client replaces it.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    conns: Arc<Mutex<HashMap<String, (u64, ClientConnection)>>>,
+}
+
+impl Notifier {
+    pub(crate) fn add(&self, connection_id: u64, conn: ClientConnection) {
+        let id = conn.client_id().to_string();
+        self.conns.lock().unwrap().insert(id, (connection_id, conn));
+    }
+
+    // no-op if the client reconnected since, the entry is the new connection's
+    pub(crate) fn remove(&self, client_id: &str, connection_id: u64) {
+        let mut conns = self.conns.lock().unwrap();
+        if matches!(conns.get(client_id), Some((id, _)) if *id == connection_id) {
+            conns.remove(client_id);
+        }
+    }
+
+    // ids of connected clients, same as RequestContext::client_id
+    pub fn client_ids(&self) -> Vec<String> {
+        self.conns.lock().unwrap().keys().cloned().collect()
+    }
+
+    // send to one client
+    pub fn notify(&self, client_id: &str, url: &str, msg: &impl Message) -> Result<(), Status> {
+        let conn = self
+            .conns
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .map(|(_, c)| c.clone());
+        let conn =
+            conn.ok_or_else(|| Status::not_found(format!("client not connected: {}", client_id)))?;
+        let msg = notification(url, msg)?;
+        send(&conn, &msg)
+    }
+
+    // send to all clients, failed sends are returned per client id
+    pub fn broadcast(&self, url: &str, msg: &impl Message) -> Result<BroadcastResult, Status> {
+        let conns: Vec<(String, ClientConnection)> = self
+            .conns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, (_, c))| (id.clone(), c.clone()))
+            .collect();
+        let msg = notification(url, msg)?;
+        let mut result = BroadcastResult::default();
+        for (client_id, conn) in conns {
+            match send(&conn, &msg) {
+                Ok(()) => result.sent += 1,
+                Err(st) => result.failed.push((client_id, st)),
+            }
+        }
+        Ok(result)
+    }
+}
+
+// outcome of Notifier::broadcast
+#[derive(Debug, Default)]
+pub struct BroadcastResult {
+    // clients it was sent to
+    pub sent: usize,
+    // client ids it could not be sent to, with the same status notify returns
+    pub failed: Vec<(String, Status)>,
+}
+
+fn send(conn: &ClientConnection, msg: &IFabricTransportMessage) -> Result<(), Status> {
+    conn.send(msg)
+        .map_err(|e| Status::unavailable(format!("send failed: {}", e.message())))
+}
+
+// notifications use the request header so the client can route by url
+fn notification(url: &str, msg: &impl Message) -> Result<IFabricTransportMessage, Status> {
+    let header = RequestHeader {
+        url: url.to_string(),
+        ..Default::default()
+    };
+    Ok(crate::sys::Message::create(
+        encode_proto(&header)?,
+        encode_proto(msg)?,
+    ))
+}
@@ -0,0 +1,98 @@
+// Protocol version and capabilities exchanged in the headers.
+// Peers from before versioning send neither, they get the original protocol:
+// no compression, protobuf bodies only and no chunking.
+
+use crate::fabricrpc_header::{ReplyHeader, RequestHeader};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// body compression, see compression
+pub const CAP_COMPRESSION: u64 = 1 << 0;
+// bodies in codecs other than protobuf, see codec
+pub const CAP_CONTENT_TYPE: u64 = 1 << 1;
+// bodies sent in frames, see chunk
+pub const CAP_CHUNKING: u64 = 1 << 2;
+
+pub const CAPABILITIES: u64 = CAP_COMPRESSION | CAP_CONTENT_TYPE | CAP_CHUNKING;
+
+// Answered by the server without dispatch. Servers before versioning
+// reply Unimplemented, which tells the client to downgrade.
+pub const HANDSHAKE_URL: &str = "/fabricrpc.Protocol/Handshake";
+
+// what the other side of a connection speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub version: u32,
+    pub capabilities: u64,
+}
+
+impl PeerInfo {
+    // peer from before versioning
+    pub const LEGACY: PeerInfo = PeerInfo {
+        version: 0,
+        capabilities: 0,
+    };
+
+    pub fn local() -> PeerInfo {
+        PeerInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        }
+    }
+
+    pub fn of_request(header: &RequestHeader) -> PeerInfo {
+        PeerInfo::new(header.protocol_version, header.capabilities)
+    }
+
+    pub fn of_reply(header: &ReplyHeader) -> PeerInfo {
+        PeerInfo::new(header.protocol_version, header.capabilities)
+    }
+
+    // capabilities mean nothing without a version
+    fn new(version: u32, capabilities: u64) -> PeerInfo {
+        if version == 0 {
+            return PeerInfo::LEGACY;
+        }
+        PeerInfo {
+            version,
+            capabilities,
+        }
+    }
+
+    pub fn supports(&self, capability: u64) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fabricrpc_header::{ReplyHeader, RequestHeader};
+
+    use super::{PeerInfo, CAP_CHUNKING, CAP_COMPRESSION, PROTOCOL_VERSION};
+
+    #[test]
+    fn peer_test() {
+        let old = PeerInfo::of_request(&RequestHeader {
+            url: String::from("/a.B/c"),
+            ..Default::default()
+        });
+        assert_eq!(PeerInfo::LEGACY, old);
+        assert!(!old.supports(CAP_COMPRESSION));
+
+        // bits without a version are ignored
+        let odd = PeerInfo::of_reply(&ReplyHeader {
+            capabilities: CAP_COMPRESSION,
+            ..Default::default()
+        });
+        assert_eq!(PeerInfo::LEGACY, odd);
+
+        let newer = PeerInfo::of_reply(&ReplyHeader {
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: CAP_CHUNKING | 1 << 40,
+            ..Default::default()
+        });
+        assert!(newer.supports(CAP_CHUNKING));
+        assert!(!newer.supports(CAP_COMPRESSION));
+        assert!(PeerInfo::local().supports(CAP_COMPRESSION | CAP_CHUNKING));
+    }
+}
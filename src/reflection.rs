@@ -0,0 +1,407 @@
+// server reflection service.
+// Lists services and methods, and serves the FileDescriptorProtos
+// emitted by fabric-rpc-build, so tools can discover and call methods.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bytes::Bytes;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use tonic::{async_trait, Status};
+
+use crate::{
+    client::{unary, Channel},
+    descriptor::{Idempotency, MethodDescriptor, ServiceDescriptor, StreamingKind},
+    server::{encode_proto, parse_proto, Service},
+};
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/fabricrpc.reflection.v1.rs"));
+}
+
+use proto::{
+    FileByFilenameRequest, FileContainingSymbolRequest, FileDescriptorResponse, ListMethodsRequest,
+    ListMethodsResponse, ListServicesRequest, ListServicesResponse, MethodResponse,
+    ServiceResponse,
+};
+
+// encoded descriptor set of the built-in reflection.proto and health.proto
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/builtin_descriptor.bin"));
+
+const SERVICE_NAME: &str = "fabricrpc.reflection.v1.ServerReflection";
+const LIST_SERVICES_URL: &str = "/fabricrpc.reflection.v1.ServerReflection/list_services";
+const LIST_METHODS_URL: &str = "/fabricrpc.reflection.v1.ServerReflection/list_methods";
+const FILE_BY_FILENAME_URL: &str = "/fabricrpc.reflection.v1.ServerReflection/file_by_filename";
+const FILE_CONTAINING_SYMBOL_URL: &str =
+    "/fabricrpc.reflection.v1.ServerReflection/file_containing_symbol";
+
+pub static SERVICE_DESCRIPTOR: ServiceDescriptor = ServiceDescriptor {
+    name: SERVICE_NAME,
+    methods: &[
+        MethodDescriptor {
+            name: "ListServices",
+            url: LIST_SERVICES_URL,
+            input_type: "fabricrpc.reflection.v1.ListServicesRequest",
+            output_type: "fabricrpc.reflection.v1.ListServicesResponse",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::NoSideEffects,
+        },
+        MethodDescriptor {
+            name: "ListMethods",
+            url: LIST_METHODS_URL,
+            input_type: "fabricrpc.reflection.v1.ListMethodsRequest",
+            output_type: "fabricrpc.reflection.v1.ListMethodsResponse",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::NoSideEffects,
+        },
+        MethodDescriptor {
+            name: "FileByFilename",
+            url: FILE_BY_FILENAME_URL,
+            input_type: "fabricrpc.reflection.v1.FileByFilenameRequest",
+            output_type: "fabricrpc.reflection.v1.FileDescriptorResponse",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::NoSideEffects,
+        },
+        MethodDescriptor {
+            name: "FileContainingSymbol",
+            url: FILE_CONTAINING_SYMBOL_URL,
+            input_type: "fabricrpc.reflection.v1.FileContainingSymbolRequest",
+            output_type: "fabricrpc.reflection.v1.FileDescriptorResponse",
+            streaming: StreamingKind::Unary,
+            idempotency: Idempotency::NoSideEffects,
+        },
+    ],
+};
+
+// builds the reflection service.
+// The reflection service always describes itself.
+pub struct Builder {
+    encoded_sets: Vec<&'static [u8]>,
+    services: Vec<&'static ServiceDescriptor>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            encoded_sets: vec![FILE_DESCRIPTOR_SET],
+            services: vec![&SERVICE_DESCRIPTOR],
+        }
+    }
+
+    // encoded FileDescriptorSet, i.e. the file written to
+    // fabric-rpc-build's file_descriptor_set_path
+    pub fn register_encoded_file_descriptor_set(mut self, encoded: &'static [u8]) -> Self {
+        self.encoded_sets.push(encoded);
+        self
+    }
+
+    pub fn register_service(mut self, desc: &'static ServiceDescriptor) -> Self {
+        if !self.services.iter().any(|s| s.name == desc.name) {
+            self.services.push(desc);
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<ReflectionService, prost::DecodeError> {
+        let mut svc = ReflectionService {
+            services: self.services,
+            files: HashMap::new(),
+            symbols: HashMap::new(),
+        };
+        for encoded in self.encoded_sets {
+            let set = FileDescriptorSet::decode(encoded)?;
+            for file in set.file {
+                svc.index_file(file);
+            }
+        }
+        Ok(svc)
+    }
+}
+
+pub struct ReflectionService {
+    services: Vec<&'static ServiceDescriptor>,
+    // file name to file
+    files: HashMap<String, FileDescriptorProto>,
+    // fully qualified symbol to file name
+    symbols: HashMap<String, String>,
+}
+
+fn qualified(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+impl ReflectionService {
+    fn index_file(&mut self, file: FileDescriptorProto) {
+        let file_name = file.name().to_string();
+        let package = file.package();
+        for msg in &file.message_type {
+            self.index_message(package, msg, &file_name);
+        }
+        for e in &file.enum_type {
+            self.symbols
+                .insert(qualified(package, e.name()), file_name.clone());
+        }
+        for svc in &file.service {
+            let svc_name = qualified(package, svc.name());
+            for m in &svc.method {
+                self.symbols
+                    .insert(qualified(&svc_name, m.name()), file_name.clone());
+            }
+            self.symbols.insert(svc_name, file_name.clone());
+        }
+        self.files.insert(file_name, file);
+    }
+
+    fn index_message(&mut self, prefix: &str, msg: &DescriptorProto, file_name: &str) {
+        let name = qualified(prefix, msg.name());
+        for nested in &msg.nested_type {
+            self.index_message(&name, nested, file_name);
+        }
+        for e in &msg.enum_type {
+            self.symbols
+                .insert(qualified(&name, e.name()), file_name.to_string());
+        }
+        self.symbols.insert(name, file_name.to_string());
+    }
+
+    // the file followed by its transitive dependencies that are known
+    fn file_response(&self, file_name: &str) -> Result<FileDescriptorResponse, Status> {
+        if !self.files.contains_key(file_name) {
+            return Err(Status::not_found(format!("file not found: {}", file_name)));
+        }
+        let mut resp = FileDescriptorResponse::default();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([file_name.to_string()]);
+        while let Some(name) = queue.pop_front() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if let Some(file) = self.files.get(&name) {
+                resp.file_descriptor_proto.push(file.encode_to_vec());
+                queue.extend(file.dependency.iter().cloned());
+            }
+        }
+        Ok(resp)
+    }
+
+    fn list_services(&self) -> ListServicesResponse {
+        ListServicesResponse {
+            service: self
+                .services
+                .iter()
+                .map(|s| ServiceResponse {
+                    name: s.name.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn list_methods(&self, req: ListMethodsRequest) -> Result<ListMethodsResponse, Status> {
+        let desc = self.services.iter().find(|s| s.name == req.service);
+        if desc.is_none() {
+            return Err(Status::not_found(format!(
+                "service not found: {}",
+                req.service
+            )));
+        }
+        let method = desc
+            .unwrap()
+            .methods
+            .iter()
+            .map(|m| MethodResponse {
+                name: m.name.to_string(),
+                url: m.url.to_string(),
+                input_type: m.input_type.to_string(),
+                output_type: m.output_type.to_string(),
+                client_streaming: matches!(
+                    m.streaming,
+                    StreamingKind::ClientStreaming | StreamingKind::BidiStreaming
+                ),
+                server_streaming: matches!(
+                    m.streaming,
+                    StreamingKind::ServerStreaming | StreamingKind::BidiStreaming
+                ),
+            })
+            .collect();
+        Ok(ListMethodsResponse { method })
+    }
+
+    fn file_containing_symbol(
+        &self,
+        req: FileContainingSymbolRequest,
+    ) -> Result<FileDescriptorResponse, Status> {
+        match self.symbols.get(&req.symbol) {
+            Some(file_name) => self.file_response(file_name),
+            None => Err(Status::not_found(format!(
+                "symbol not found: {}",
+                req.symbol
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl Service for ReflectionService {
+    fn name(&self) -> String {
+        String::from(SERVICE_NAME)
+    }
+
+    fn descriptor(&self) -> Option<&'static ServiceDescriptor> {
+        Some(&SERVICE_DESCRIPTOR)
+    }
+
+    async fn handle_request(
+        &self,
+        url: String,
+        request: Bytes,
+    ) -> std::result::Result<Bytes, tonic::Status> {
+        match url.as_str() {
+            LIST_SERVICES_URL => {
+                let _req: ListServicesRequest = parse_proto(&request)?;
+                encode_proto(&self.list_services())
+            }
+            LIST_METHODS_URL => {
+                let req = parse_proto(&request)?;
+                encode_proto(&self.list_methods(req)?)
+            }
+            FILE_BY_FILENAME_URL => {
+                let req: FileByFilenameRequest = parse_proto(&request)?;
+                encode_proto(&self.file_response(&req.filename)?)
+            }
+            FILE_CONTAINING_SYMBOL_URL => {
+                let req = parse_proto(&request)?;
+                encode_proto(&self.file_containing_symbol(req)?)
+            }
+            _ => Err(tonic::Status::unimplemented("url not found")),
+        }
+    }
+}
+
+// client for the reflection service over any channel
+pub struct ReflectionClient<C> {
+    c: C,
+}
+
+impl<C: Channel> ReflectionClient<C> {
+    pub fn new(channel: C) -> ReflectionClient<C> {
+        ReflectionClient { c: channel }
+    }
+
+    pub async fn list_services(
+        &self,
+        timoutmilliseconds: u32,
+    ) -> Result<ListServicesResponse, Status> {
+        let url = String::from(LIST_SERVICES_URL);
+        unary(&self.c, url, &ListServicesRequest {}, timoutmilliseconds).await
+    }
+
+    pub async fn list_methods(
+        &self,
+        timoutmilliseconds: u32,
+        request: ListMethodsRequest,
+    ) -> Result<ListMethodsResponse, Status> {
+        let url = String::from(LIST_METHODS_URL);
+        unary(&self.c, url, &request, timoutmilliseconds).await
+    }
+
+    pub async fn file_by_filename(
+        &self,
+        timoutmilliseconds: u32,
+        request: FileByFilenameRequest,
+    ) -> Result<FileDescriptorResponse, Status> {
+        let url = String::from(FILE_BY_FILENAME_URL);
+        unary(&self.c, url, &request, timoutmilliseconds).await
+    }
+
+    pub async fn file_containing_symbol(
+        &self,
+        timoutmilliseconds: u32,
+        request: FileContainingSymbolRequest,
+    ) -> Result<FileDescriptorResponse, Status> {
+        let url = String::from(FILE_CONTAINING_SYMBOL_URL);
+        unary(&self.c, url, &request, timoutmilliseconds).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use prost_types::FileDescriptorProto;
+    use tonic::Code;
+
+    use super::{
+        proto::{
+            FileByFilenameRequest, FileContainingSymbolRequest, ListMethodsRequest,
+            ListServicesRequest,
+        },
+        Builder, ReflectionService,
+    };
+    use crate::server::{encode_proto, Service};
+
+    async fn call<T: Message + Default>(
+        svc: &ReflectionService,
+        method: &str,
+        req: &impl Message,
+    ) -> Result<T, tonic::Status> {
+        let url = format!("/fabricrpc.reflection.v1.ServerReflection/{}", method);
+        let body = svc.handle_request(url, encode_proto(req)?).await?;
+        Ok(T::decode(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn reflection_test() {
+        let svc = Builder::new().build().unwrap();
+
+        let resp: super::ListServicesResponse =
+            call(&svc, "list_services", &ListServicesRequest {})
+                .await
+                .unwrap();
+        assert_eq!(1, resp.service.len());
+        assert_eq!(
+            "fabricrpc.reflection.v1.ServerReflection",
+            resp.service[0].name
+        );
+
+        let req = ListMethodsRequest {
+            service: resp.service[0].name.clone(),
+        };
+        let resp: super::ListMethodsResponse = call(&svc, "list_methods", &req).await.unwrap();
+        assert_eq!(4, resp.method.len());
+        assert_eq!("ListServices", resp.method[0].name);
+
+        let req = FileContainingSymbolRequest {
+            symbol: String::from("fabricrpc.reflection.v1.ListServicesRequest"),
+        };
+        let resp: super::FileDescriptorResponse =
+            call(&svc, "file_containing_symbol", &req).await.unwrap();
+        assert_eq!(1, resp.file_descriptor_proto.len());
+        let file = FileDescriptorProto::decode(resp.file_descriptor_proto[0].as_slice()).unwrap();
+        assert_eq!("reflection.proto", file.name());
+
+        let req = FileByFilenameRequest {
+            filename: String::from("reflection.proto"),
+        };
+        let resp: super::FileDescriptorResponse =
+            call(&svc, "file_by_filename", &req).await.unwrap();
+        assert_eq!(1, resp.file_descriptor_proto.len());
+
+        let req = FileContainingSymbolRequest {
+            symbol: String::from("not.Exist"),
+        };
+        let err = call::<super::FileDescriptorResponse>(&svc, "file_containing_symbol", &req)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+    }
+}
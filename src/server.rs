@@ -1,28 +1,138 @@
 // server
 
-use std::{future::Future, sync::Arc};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 
+use bytes::Bytes;
 use fabric_base::{
-    FabricCommon::FabricTransport::{FABRIC_TRANSPORT_LISTEN_ADDRESS, FABRIC_TRANSPORT_SETTINGS},
+    FabricCommon::FabricTransport::{
+        IFabricTransportMessage, FABRIC_TRANSPORT_LISTEN_ADDRESS, FABRIC_TRANSPORT_SETTINGS,
+    },
     FABRIC_SECURITY_CREDENTIALS, FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
 };
+use futures::FutureExt;
 use prost::Message;
+use tokio::{
+    sync::{broadcast, oneshot, watch, Semaphore},
+    task::JoinSet,
+};
 use tonic::async_trait;
+use tower::{buffer::Buffer, util::BoxService, BoxError, Layer, ServiceExt};
+use tracing::{field, Instrument};
 use windows::core::{HSTRING, PCWSTR};
 
 use crate::{
-    fabricrpc_header::{ReplyHeader, RequestHeader},
-    server_tr::ServerTransport,
+    buffer,
+    chunk::{ChunkConfig, Reassembler, ReplyFrame, ReplyFrames},
+    codec,
+    compression::CompressionConfig,
+    connection::{ConnectionEvent, ConnectionState},
+    context::RequestContext,
+    descriptor::{ServiceDescriptor, StreamingKind},
+    fabricrpc_header::{ChunkInfo, ReplyHeader, RequestHeader},
+    metrics::{CallOutcome, MetricsRecorder, Side},
+    middleware::{status_from_error, DispatchService, FabricRequest},
+    notify::Notifier,
+    protocol::{self, PeerInfo, CAP_CHUNKING, CAP_COMPRESSION},
+    server_tr::{ServerRequest, ServerTransport, ServerTransportOptions},
     sys::MessageViewer,
+    trace::{self, TraceContext},
 };
 
-#[derive(Default)]
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const DEFAULT_CONNECTION_QUEUE_SIZE: usize = 100;
+const DEFAULT_CONNECTION_CONCURRENCY_LIMIT: usize = 16;
+// connection events buffered per subscriber before it lags
+const CONNECTION_EVENT_CAPACITY: usize = 64;
+// requests waiting for the layer stack to be ready
+const DISPATCH_BUFFER_SIZE: usize = 1024;
+
+type BoxLayer = Box<dyn Fn(DispatchService) -> DispatchService + Send + Sync>;
+
 pub struct Server {
     svcs: Vec<Box<dyn Service>>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    layers: Vec<BoxLayer>,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+    compression: CompressionConfig,
+    chunking: ChunkConfig,
+    notifier: Notifier,
+    events: broadcast::Sender<ConnectionEvent>,
+    panics: PanicCounter,
+    grace_period: Duration,
+    concurrency_limit: Option<usize>,
+    connection_queue_size: usize,
+    connection_concurrency_limit: usize,
+    method_limits: HashMap<String, usize>,
+    ordered_urls: HashSet<String>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            svcs: Vec::new(),
+            interceptors: Vec::new(),
+            layers: Vec::new(),
+            metrics: None,
+            compression: CompressionConfig::default(),
+            chunking: ChunkConfig::default(),
+            notifier: Notifier::default(),
+            events: broadcast::channel(CONNECTION_EVENT_CAPACITY).0,
+            panics: PanicCounter::default(),
+            grace_period: DEFAULT_GRACE_PERIOD,
+            concurrency_limit: None,
+            connection_queue_size: DEFAULT_CONNECTION_QUEUE_SIZE,
+            connection_concurrency_limit: DEFAULT_CONNECTION_CONCURRENCY_LIMIT,
+            method_limits: HashMap::new(),
+            ordered_urls: HashSet::new(),
+        }
+    }
+}
+
+// what happened to requests when the server shut down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    // in-flight requests that completed within the grace period
+    pub drained: usize,
+    // in-flight requests still running or queued when the grace period expired,
+    // replied with Unavailable
+    pub aborted: usize,
+    // requests arriving after shutdown, replied with Unavailable
+    pub rejected: usize,
 }
 
+#[derive(Clone)]
 struct ServerInner {
     svcs: Arc<Vec<Box<dyn Service>>>,
+    interceptors: Arc<Vec<Box<dyn Interceptor>>>,
+    // tower stack around dispatch, none without layers.
+    // One stack serves all requests, so layers keep their state across calls.
+    dispatch: Option<Arc<Mutex<Buffer<DispatchService, FabricRequest>>>>,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+    compression: Arc<CompressionConfig>,
+    chunking: ChunkConfig,
+    notifier: Notifier,
+    events: broadcast::Sender<ConnectionEvent>,
+    panics: PanicCounter,
+    grace_period: Duration,
+    connection_queue_size: usize,
+    connection_concurrency_limit: usize,
+    // shared by all connections, handlers wait for a permit
+    global_limit: Option<Arc<Semaphore>>,
+    // per url, requests over the limit are shed
+    method_limits: Arc<HashMap<String, Arc<Semaphore>>>,
+    ordered_urls: Arc<HashSet<String>>,
 }
 
 impl Server {
@@ -30,51 +140,353 @@ impl Server {
     //   ServerInner { svcs: Arc::new(self.svcs) }
     // }
 
+    // max time to wait for in-flight requests on shutdown before force closing
+    pub fn grace_period(&mut self, period: Duration) -> &mut Self {
+        self.grace_period = period;
+        self
+    }
+
+    // max handlers running at once across all connections.
+    // Requests over the limit wait in their connection queue.
+    pub fn concurrency_limit(&mut self, limit: usize) -> &mut Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    // max requests queued per connection. When the queue is full
+    // the request is rejected with ResourceExhausted without blocking the transport.
+    pub fn connection_queue_size(&mut self, size: usize) -> &mut Self {
+        assert!(size > 0, "connection queue size must be positive");
+        self.connection_queue_size = size;
+        self
+    }
+
+    // max requests from one connection handled at once.
+    // Further requests stay in the connection queue.
+    pub fn connection_concurrency_limit(&mut self, limit: usize) -> &mut Self {
+        assert!(limit > 0, "connection concurrency limit must be positive");
+        self.connection_concurrency_limit = limit;
+        self
+    }
+
+    // requests to this url from the same connection are handled one at a time
+    // in arrival order. Other requests are dispatched concurrently.
+    pub fn preserve_order(&mut self, url: &str) -> &mut Self {
+        self.ordered_urls.insert(url.to_string());
+        self
+    }
+
+    // max concurrent calls to one method, url like /package.Service/method.
+    // Calls over the limit are rejected with ResourceExhausted.
+    pub fn method_concurrency_limit(&mut self, url: &str, limit: usize) -> &mut Self {
+        self.method_limits.insert(url.to_string(), limit);
+        self
+    }
+
     pub fn add_service<T: Service + 'static>(&mut self, svc: T) {
         self.svcs.push(Box::new(svc));
     }
 
-    pub async fn serve_with_shutdown<F: Future<Output = ()>>(self, port: u32, signal: F) {
+    // interceptors run in order before every request is dispatched
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) -> &mut Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    // wrap dispatch in a tower layer, e.g. a ServiceBuilder stack.
+    // Layers run after interceptors, the first one added is the outermost.
+    pub fn layer<L>(&mut self, layer: L) -> &mut Self
+    where
+        L: Layer<DispatchService> + Send + Sync + 'static,
+        L::Service: tower::Service<FabricRequest, Response = Bytes> + Send + 'static,
+        <L::Service as tower::Service<FabricRequest>>::Error: Into<BoxError>,
+        <L::Service as tower::Service<FabricRequest>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |svc| {
+            BoxService::new(layer.layer(svc).map_err(Into::into))
+        }));
+        self
+    }
+
+    // record calls and connections, see metrics::MetricsRecorder
+    pub fn metrics(&mut self, recorder: Arc<dyn MetricsRecorder>) -> &mut Self {
+        self.metrics = Some(recorder);
+        self
+    }
+
+    // encoding of reply bodies and the request encodings the server accepts.
+    // Replies are only compressed with an encoding the client accepts.
+    pub fn compression(&mut self, config: CompressionConfig) -> &mut Self {
+        self.compression = config;
+        self
+    }
+
+    // Bodies that do not fit in one msg are sent in frames and reassembled
+    // up to max_size per connection, see chunk::ChunkConfig.
+    // Replies are only chunked for clients that can reassemble them.
+    pub fn chunking(&mut self, config: ChunkConfig) -> &mut Self {
+        config.check();
+        self.chunking = config;
+        self
+    }
+
+    // handle to push notifications to clients once the server is running
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    // connect and disconnect events of clients, subscribe before serving
+    pub fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    // handler panics caught by the server, for alerting
+    pub fn panic_counter(&self) -> PanicCounter {
+        self.panics.clone()
+    }
+
+    // descriptors of the registered services that have one
+    pub fn descriptors(&self) -> Vec<&'static ServiceDescriptor> {
+        self.svcs
+            .iter()
+            .filter_map(|svc| svc.descriptor())
+            .collect()
+    }
+
+    // add the reflection service describing all services added so far
+    pub fn add_reflection_service(
+        &mut self,
+        builder: crate::reflection::Builder,
+    ) -> Result<(), prost::DecodeError> {
+        let builder = self
+            .descriptors()
+            .into_iter()
+            .fold(builder, |b, desc| b.register_service(desc));
+        self.add_service(builder.build()?);
+        Ok(())
+    }
+
+    // urls of all methods known from descriptors
+    pub fn routes(&self) -> Vec<&'static str> {
+        self.descriptors()
+            .iter()
+            .flat_map(|desc| desc.methods.iter().map(|m| m.url))
+            .collect()
+    }
+
+    // serve until signal fires, then stop accepting and drain in-flight requests
+    pub async fn serve_with_shutdown<F: Future<Output = ()>>(
+        self,
+        port: u32,
+        signal: F,
+    ) -> ShutdownSummary {
+        let method_limits = self
+            .method_limits
+            .into_iter()
+            .map(|(url, limit)| (url, Arc::new(Semaphore::new(limit))))
+            .collect();
+        let svcs = Arc::new(self.svcs);
+        let dispatch = if self.layers.is_empty() {
+            None
+        } else {
+            let router = BoxService::new(Router { svcs: svcs.clone() });
+            let stack = self
+                .layers
+                .iter()
+                .rev()
+                .fold(router, |svc, layer| layer(svc));
+            Some(Arc::new(Mutex::new(Buffer::new(
+                stack,
+                DISPATCH_BUFFER_SIZE,
+            ))))
+        };
         let mut inner = ServerInner {
-            svcs: Arc::new(self.svcs),
+            svcs,
+            interceptors: Arc::new(self.interceptors),
+            dispatch,
+            metrics: self.metrics,
+            compression: Arc::new(self.compression),
+            chunking: self.chunking,
+            notifier: self.notifier,
+            events: self.events,
+            panics: self.panics,
+            grace_period: self.grace_period,
+            connection_queue_size: self.connection_queue_size,
+            connection_concurrency_limit: self.connection_concurrency_limit,
+            global_limit: self.concurrency_limit.map(|n| Arc::new(Semaphore::new(n))),
+            method_limits: Arc::new(method_limits),
+            ordered_urls: Arc::new(self.ordered_urls),
         };
         inner.serve_with_shutdown(port, signal).await
     }
 }
 
+// find the service for url and handle the request
+async fn route(
+    svcs: &[Box<dyn Service>],
+    url: String,
+    content_type: &str,
+    body: Bytes,
+) -> Result<Bytes, tonic::Status> {
+    let url_without_prefix = &url.as_bytes()[1..];
+
+    for svc in svcs.iter() {
+        let svc_url = svc.name();
+        if !url_without_prefix.starts_with(svc_url.as_bytes()) {
+            continue;
+        }
+        // validate url before dispatch if the service describes itself
+        if let Some(desc) = svc.descriptor() {
+            match desc.method_by_url(&url) {
+                None => continue,
+                Some(m) if m.streaming != StreamingKind::Unary => {
+                    return Err(tonic::Status::unimplemented("streaming not supported"));
+                }
+                Some(_) => {}
+            }
+        }
+        if !codec::content_type_matches(svc.content_type(), content_type) {
+            return Err(tonic::Status::unimplemented(format!(
+                "content type {} not supported by {}, use {}",
+                content_type,
+                svc_url,
+                svc.content_type()
+            )));
+        }
+        return svc.handle_request(url, body).await;
+    }
+    Err(tonic::Status::unimplemented("url not found"))
+}
+
+// innermost service of the tower stack
+#[derive(Clone)]
+struct Router {
+    svcs: Arc<Vec<Box<dyn Service>>>,
+}
+
+impl tower::Service<FabricRequest> for Router {
+    type Response = Bytes;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Bytes, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: FabricRequest) -> Self::Future {
+        let svcs = self.svcs.clone();
+        Box::pin(async move {
+            route(&svcs, req.header.url, &req.header.content_type, req.body)
+                .await
+                .map_err(Into::into)
+        })
+    }
+}
+
 impl ServerInner {
     // internal execute request
-    async fn execute(&mut self, msgvw: MessageViewer<'_>) -> Result<Vec<u8>, tonic::Status> {
-        //let msgvw = MessageViewer::new(msg);
-        let header_buff = msgvw.get_header();
-        let body_buff = msgvw.get_body();
-
-        let header = RequestHeader::decode(header_buff);
+    async fn execute(
+        &self,
+        header: Result<RequestHeader, prost::DecodeError>,
+        body_buff: Bytes,
+        mut ctx: RequestContext,
+    ) -> Result<Bytes, tonic::Status> {
         if let Err(err) = header {
             let mut err_str = String::from("header invalid, failed to parse");
             err_str.push_str(&err.to_string());
             return Err(tonic::Status::invalid_argument(err_str));
         }
 
-        let url = header.unwrap().url;
+        let header = header.unwrap();
+        let url = header.url;
         if url.is_empty() || !url.starts_with('/') {
             return Err(tonic::Status::invalid_argument("url not valid"));
         }
+        let body =
+            self.compression
+                .decompress(&header.encoding, &body_buff, self.chunking.max_size)?;
 
-        let url_without_prefix = &url.as_bytes()[1..];
+        // continue the client's trace, or start one
+        let parent = TraceContext::from_metadata(&header.metadata);
+        let trace = parent.map_or_else(TraceContext::new_root, |p| p.child());
+        let span = tracing::info_span!(
+            "fabric_rpc.server",
+            url = %url,
+            client_id = %ctx.client_id(),
+            connection_id = ctx.connection_id(),
+            trace_id = %trace.trace_id(),
+            span_id = %trace.span_id(),
+            parent_span_id = field::Empty,
+            request_size = body_buff.len(),
+            reply_size = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        if let Some(parent) = parent {
+            span.record("parent_span_id", parent.span_id().as_str());
+        }
 
-        for svc in self.svcs.iter() {
-            let svc_url = svc.name();
-            if !url_without_prefix.starts_with(svc_url.as_bytes()) {
-                continue;
-            }
-            let result = svc.handle_request(url, body_buff).await?;
-            return Ok(result);
+        ctx.set_request(url.clone(), header.content_type, header.metadata);
+        ctx.set_trace_context(trace);
+        let received_at = ctx.received_at();
+        let result = self.handle(url, body, ctx).instrument(span.clone()).await;
+        // latency includes the time queued
+        trace::record_result(&span, &result, received_at);
+        result
+    }
+
+    // interceptors, limits and dispatch of a valid request
+    async fn handle(
+        &self,
+        url: String,
+        body_buff: Bytes,
+        mut ctx: RequestContext,
+    ) -> Result<Bytes, tonic::Status> {
+        for interceptor in self.interceptors.iter() {
+            interceptor.call(&mut ctx)?;
         }
-        Err(tonic::Status::unimplemented("url not found"))
+
+        // shed load instead of queueing behind a saturated method
+        let _permit = match self.method_limits.get(&url) {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    return Err(tonic::Status::resource_exhausted(
+                        "method concurrency limit reached",
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        let dispatch = match self.dispatch.as_ref() {
+            Some(dispatch) => dispatch,
+            None => {
+                let content_type = ctx.content_type().to_string();
+                return ctx
+                    .scope(route(&self.svcs, url, &content_type, body_buff))
+                    .await;
+            }
+        };
+        // handle to the shared stack, not a copy of it
+        let dispatch = dispatch.lock().unwrap().clone();
+        // layers get an owned request, the body is shared
+        let timeout_milliseconds = match ctx.deadline() {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis() as u32,
+            None => u32::MAX,
+        };
+        let mut req = FabricRequest::new(url, body_buff, timeout_milliseconds);
+        req.header.content_type = ctx.content_type().to_string();
+        req.header.metadata = ctx.metadata().clone();
+        req.one_way = ctx.is_one_way();
+        ctx.scope(dispatch.oneshot(req))
+            .await
+            .map_err(status_from_error)
     }
 
-    async fn serve_with_shutdown<F>(&mut self, port: u32, signal: F)
+    async fn serve_with_shutdown<F>(&mut self, port: u32, signal: F) -> ShutdownSummary
     where
         F: Future<Output = ()>,
     {
@@ -87,7 +499,7 @@ impl ServerInner {
             let settings = FABRIC_TRANSPORT_SETTINGS {
                 OperationTimeoutInSeconds: 10,
                 KeepAliveTimeoutInSeconds: 10,
-                MaxMessageSize: 1024,
+                MaxMessageSize: self.chunking.max_message_size as u32,
                 MaxConcurrentCalls: 10,
                 MaxQueueSize: 10,
                 SecurityCredentials: &creds,
@@ -101,7 +513,16 @@ impl ServerInner {
             serveraddr.IPAddressOrFQDN = PCWSTR(host.as_ptr());
             serveraddr.Port = port;
             serveraddr.Path = PCWSTR(path.as_ptr());
-            listener = ServerTransport::new(&settings, &serveraddr).unwrap();
+            let options = ServerTransportOptions {
+                connection_queue_size: self.connection_queue_size,
+                busy_reply: Some(Arc::new(|| {
+                    reply_message(Err(tonic::Status::resource_exhausted(
+                        "connection queue is full",
+                    )))
+                })),
+                metrics: self.metrics.clone(),
+            };
+            listener = ServerTransport::new_with_options(&settings, &serveraddr, options).unwrap();
         }
 
         let _ = listener.open().await.unwrap();
@@ -109,68 +530,445 @@ impl ServerInner {
         //let connectionaddress = HSTRING::from("localhost:12345+/");
         // assert_eq!(listen_addr, connectionaddress);
 
+        // shared with connection tasks to drain on shutdown
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let rejected = Arc::new(AtomicUsize::new(0));
+        let (in_flight_tx, mut in_flight_rx) = watch::channel(0_usize);
+        let in_flight_tx = Arc::new(in_flight_tx);
+        let outstanding = Arc::new(Outstanding::default());
+        let mut conns = JoinSet::new();
+        let mut next_connection_id: u64 = 0;
+
         let mut p = Box::pin(signal);
         loop {
             let mut conn;
             tokio::select! {
                 _ = (&mut p) => { break;},
+                // reap finished connection tasks
+                Some(_) = conns.join_next(), if !conns.is_empty() => { continue; },
                 x = listener.async_accept() => {
                     conn = x;
                 }
             }
-            //println!("Server got connection");
+            tracing::debug!(
+                client_id = %conn.client().client_id(),
+                connection_id = next_connection_id,
+                "connection accepted"
+            );
+
+            let inner_clone = self.clone();
+            let connection_id = next_connection_id;
+            next_connection_id += 1;
+            let shutdown = shutdown.clone();
+            let rejected = rejected.clone();
+            let in_flight_tx = in_flight_tx.clone();
+            let outstanding = outstanding.clone();
 
-            let mut inner_clone = ServerInner {
-                svcs: self.svcs.clone(),
+            let client_id = conn.client().client_id().to_string();
+            inner_clone
+                .notifier
+                .add(connection_id, conn.client().clone());
+            let _ = self.events.send(ConnectionEvent::Connected {
+                client_id: client_id.clone(),
+                connection_id,
+            });
+            let guard = ConnectionGuard {
+                client_id,
+                connection_id,
+                state: ConnectionState::default(),
+                notifier: self.notifier.clone(),
+                events: self.events.clone(),
             };
 
-            tokio::spawn(async move {
+            conns.spawn(async move {
+                // dropped last, after the requests below
+                let guard = guard;
+                // requests are handled concurrently. Dropping the set on abort
+                // cancels them together with the connection.
+                let mut reqs = JoinSet::new();
+                let conn_limit = Arc::new(Semaphore::new(inner_clone.connection_concurrency_limit));
+                // completion of the last request per ordered url
+                let mut ordered_tail: HashMap<String, oneshot::Receiver<()>> = HashMap::new();
+                // bodies sent in chunks on this connection
+                let mut incoming = Reassembler::new(inner_clone.chunking);
+                let outgoing = Arc::new(Mutex::new(ReplyFrames::new(inner_clone.chunking)));
                 // loop until the request from this server is drained.
                 loop {
-                    let req = conn.async_accept().await;
+                    let req;
+                    tokio::select! {
+                        Some(_) = reqs.join_next(), if !reqs.is_empty() => { continue; },
+                        x = conn.async_accept() => {
+                            req = x;
+                        }
+                    }
                     if req.is_none() {
                         break;
                     }
                     let mut req = req.unwrap();
-                    //println!("Server got request");
 
-                    let vw;
-                    {
-                        let msg = req.get_request_msg();
-                        vw = MessageViewer::new(msg.clone());
+                    if shutdown.load(Ordering::Acquire) {
+                        rejected.fetch_add(1, Ordering::Relaxed);
+                        let st = tonic::Status::unavailable("server is shutting down");
+                        req.complete(reply_message(Err(st)));
+                        continue;
                     }
-                    // let header = vw.get_header();
-                    // let body = vw.get_body();
 
-                    let payload = inner_clone.execute(vw).await;
-
-                    let mut replyheader = ReplyHeader::default();
-                    let mut replybody = Vec::new();
-                    match payload {
-                        Err(st) => {
-                            replyheader.status_code = st.code() as i32;
-                            replyheader.status_message = String::from(st.message());
-                        }
-                        Ok(content) => {
-                            replyheader.status_code = tonic::Code::Ok as i32;
-                            replyheader.status_message = String::from("Ok");
-                            replybody = content;
+                    // the body shares the request msg, no copy
+                    let (header_buff, body) =
+                        MessageViewer::new(req.get_request_msg().clone()).into_bytes();
+                    let mut header = RequestHeader::decode(header_buff);
+                    let body = match header.as_mut() {
+                        // the reply header tells the client what this server speaks
+                        Ok(h) if h.url == protocol::HANDSHAKE_URL => {
+                            req.complete(reply_message(Ok((Bytes::new(), String::new()))));
+                            continue;
                         }
-                    }
+                        Ok(h) => match receive_frame(h, body, &mut incoming, &outgoing) {
+                            Frame::Request(body) => body,
+                            Frame::Reply(reply) => {
+                                req.complete(reply);
+                                continue;
+                            }
+                        },
+                        Err(_) => body,
+                    };
 
-                    let header_buff = encode_proto(&replyheader).unwrap();
+                    // in flight from here, also while queued behind the limit
+                    in_flight_tx.send_modify(|n| *n += 1);
+                    let ctx = RequestContext::new(
+                        req.client_id().to_string(),
+                        connection_id,
+                        guard.state.clone(),
+                        req.received_at(),
+                        req.timeout_milliseconds(),
+                        req.is_one_way(),
+                    );
+                    let received_at = req.received_at();
+                    let req_id = outstanding.insert(req);
+
+                    // stop pulling from the queue while the connection is at its limit
+                    let conn_permit = conn_limit.clone().acquire_owned().await.unwrap();
+                    let url = header.as_ref().map(|h| h.url.clone()).unwrap_or_default();
+                    // older clients get replies they can read
+                    let peer = header
+                        .as_ref()
+                        .map_or(PeerInfo::LEGACY, PeerInfo::of_request);
+                    let accept = match &header {
+                        Ok(h) if peer.supports(CAP_COMPRESSION) => h.accept_encoding.clone(),
+                        _ => Vec::new(),
+                    };
+                    // replies over this are sent in frames
+                    let reply_frame_size = match &header {
+                        Ok(h) if peer.supports(CAP_CHUNKING) && h.max_reply_frame > 0 => Some(
+                            inner_clone
+                                .chunking
+                                .reply_frame_size()
+                                .min(h.max_reply_frame as usize),
+                        ),
+                        _ => None,
+                    };
+                    // chain ordered requests so each waits for the previous one
+                    let order = match &header {
+                        Ok(h) if inner_clone.ordered_urls.contains(&h.url) => {
+                            let (done_tx, done_rx) = oneshot::channel::<()>();
+                            let prev = ordered_tail.insert(h.url.clone(), done_rx);
+                            Some((prev, done_tx))
+                        }
+                        _ => None,
+                    };
 
-                    let reply = crate::sys::Message::create(header_buff, replybody);
-                    req.complete(reply);
+                    let inner = inner_clone.clone();
+                    let in_flight_tx = in_flight_tx.clone();
+                    let outgoing = outgoing.clone();
+                    let outstanding = outstanding.clone();
+                    let record = inner.metrics.clone().map(|metrics| {
+                        CallRecord::start(metrics, url.clone(), received_at, body.len())
+                    });
+                    reqs.spawn(async move {
+                        let _conn_permit = conn_permit;
+                        let _done = match order {
+                            Some((prev, done_tx)) => {
+                                if let Some(prev) = prev {
+                                    // sender dropped also means the previous one finished
+                                    let _ = prev.await;
+                                }
+                                Some(done_tx)
+                            }
+                            None => None,
+                        };
+                        let _permit = match inner.global_limit.as_ref() {
+                            Some(limit) => limit.clone().acquire_owned().await.ok(),
+                            None => None,
+                        };
+                        // a panicking handler must still reply, or the client hangs
+                        let payload = AssertUnwindSafe(inner.execute(header, body, ctx))
+                            .catch_unwind()
+                            .await
+                            .unwrap_or_else(|panic| {
+                                inner.panics.increment();
+                                tracing::error!(
+                                    url = %url,
+                                    panic = panic_message(panic.as_ref()),
+                                    "handler panicked"
+                                );
+                                Err(tonic::Status::internal("handler panicked"))
+                            });
+                        // recorded here, the reply is sent right after
+                        if let Some(mut record) = record {
+                            record.finish(&payload);
+                        }
+                        let reply = payload.and_then(|body| {
+                            inner.compression.compress(body, Some(accept.as_slice()))
+                        });
+                        let reply = match (reply, reply_frame_size) {
+                            // the client pulls the other frames
+                            (Ok((body, encoding)), Some(frame_size)) if body.len() > frame_size => {
+                                let first =
+                                    outgoing.lock().unwrap().start(body, encoding, frame_size);
+                                frame_message(first)
+                            }
+                            (reply, _) => reply_message(reply),
+                        };
+                        outstanding.complete(req_id, reply);
+                        in_flight_tx.send_modify(|n| *n -= 1);
+                    });
                 }
+                // connection closed, let the remaining requests finish
+                while reqs.join_next().await.is_some() {}
             });
         }
-        //tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-        //println!("Server shutdown");
+
+        // stop taking new requests and wait for in-flight ones
+        shutdown.store(true, Ordering::Release);
+        for svc in self.svcs.iter() {
+            svc.on_shutdown();
+        }
+        let in_flight = *in_flight_rx.borrow();
+        let drained_in_time =
+            tokio::time::timeout(self.grace_period, in_flight_rx.wait_for(|n| *n == 0))
+                .await
+                .is_ok();
+        let aborted = if drained_in_time {
+            0
+        } else {
+            *in_flight_rx.borrow()
+        };
+
+        // reply to whatever is left, then force close it
+        outstanding.complete_all(tonic::Status::unavailable("server shut down"));
+        conns.abort_all();
+        while conns.join_next().await.is_some() {}
+        tracing::info!(in_flight, aborted, "server shut down");
         listener.close().await.unwrap();
+
+        ShutdownSummary {
+            drained: in_flight.saturating_sub(aborted),
+            aborted,
+            rejected: rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// number of handler panics caught
+#[derive(Debug, Clone, Default)]
+pub struct PanicCounter {
+    count: Arc<AtomicUsize>,
+}
+
+impl PanicCounter {
+    fn increment(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+// in flight in the metrics until dropped, so calls aborted on shutdown finish
+// too, as Unavailable like their reply
+struct CallRecord {
+    metrics: Arc<dyn MetricsRecorder>,
+    url: String,
+    received_at: Instant,
+    request_size: usize,
+    // code and reply size, none until the handler returned
+    result: Option<(tonic::Code, usize)>,
+}
+
+impl CallRecord {
+    fn start(
+        metrics: Arc<dyn MetricsRecorder>,
+        url: String,
+        received_at: Instant,
+        request_size: usize,
+    ) -> CallRecord {
+        metrics.call_started(Side::Server, &url);
+        CallRecord {
+            metrics,
+            url,
+            received_at,
+            request_size,
+            result: None,
+        }
+    }
+
+    fn finish(&mut self, payload: &Result<Bytes, tonic::Status>) {
+        self.result = Some(match payload {
+            Ok(body) => (tonic::Code::Ok, body.len()),
+            Err(st) => (st.code(), 0),
+        });
+    }
+}
+
+impl Drop for CallRecord {
+    fn drop(&mut self) {
+        let (code, reply_size) = self.result.unwrap_or((tonic::Code::Unavailable, 0));
+        let outcome = CallOutcome {
+            code,
+            latency: self.received_at.elapsed(),
+            request_size: self.request_size,
+            reply_size,
+        };
+        self.metrics
+            .call_finished(Side::Server, &self.url, &outcome);
     }
 }
 
+// requests received and not replied yet, so shutdown can reply to the ones
+// it aborts
+#[derive(Default)]
+struct Outstanding {
+    next_id: AtomicU64,
+    reqs: Mutex<HashMap<u64, ServerRequest>>,
+}
+
+impl Outstanding {
+    fn insert(&self, req: ServerRequest) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.reqs.lock().unwrap().insert(id, req);
+        id
+    }
+
+    // no-op if shutdown replied already
+    fn complete(&self, id: u64, reply: IFabricTransportMessage) {
+        let req = self.reqs.lock().unwrap().remove(&id);
+        if let Some(mut req) = req {
+            req.complete(reply);
+        }
+    }
+
+    fn complete_all(&self, st: tonic::Status) {
+        let reqs = std::mem::take(&mut *self.reqs.lock().unwrap());
+        for (_, mut req) in reqs {
+            req.complete(reply_message(Err(st.clone())));
+        }
+    }
+}
+
+// reports the connection gone when its task ends, also when aborted on shutdown
+struct ConnectionGuard {
+    client_id: String,
+    connection_id: u64,
+    state: ConnectionState,
+    notifier: Notifier,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.notifier.remove(&self.client_id, self.connection_id);
+        let _ = self.events.send(ConnectionEvent::Disconnected {
+            client_id: self.client_id.clone(),
+            connection_id: self.connection_id,
+            state: self.state.clone(),
+        });
+    }
+}
+
+// what to do with a request msg, that may be a frame of a chunked body
+enum Frame {
+    // complete request to dispatch
+    Request(Bytes),
+    // replied without dispatch
+    Reply(IFabricTransportMessage),
+}
+
+// Frames of a chunked request are acked until the body is complete,
+// pulls of a chunked reply are served from the pending replies.
+fn receive_frame(
+    header: &mut RequestHeader,
+    body: Bytes,
+    incoming: &mut Reassembler,
+    outgoing: &Mutex<ReplyFrames>,
+) -> Frame {
+    if let Some(info) = header.reply_chunk.take() {
+        let frame = outgoing.lock().unwrap().frame(info.transfer_id, info.seq);
+        return Frame::Reply(frame_message(frame));
+    }
+    let info = match header.chunk.take() {
+        Some(info) => info,
+        None => return Frame::Request(body),
+    };
+    match incoming.push(&info, body) {
+        Ok(Some(body)) => Frame::Request(body),
+        Ok(None) => Frame::Reply(reply_message(Ok((Bytes::new(), String::new())))),
+        Err(st) => Frame::Reply(reply_message(Err(st))),
+    }
+}
+
+fn frame_message(frame: Result<ReplyFrame, tonic::Status>) -> IFabricTransportMessage {
+    match frame {
+        Ok(frame) => reply_chunk(Ok((frame.body, frame.encoding)), Some(frame.info)),
+        Err(st) => reply_message(Err(st)),
+    }
+}
+
+// build the reply msg with the status in header.
+// payload is the body and its encoding, empty if not compressed.
+fn reply_message(payload: Result<(Bytes, String), tonic::Status>) -> IFabricTransportMessage {
+    reply_chunk(payload, None)
+}
+
+// reply msg with the frame position if the body is a frame of a chunked reply
+fn reply_chunk(
+    payload: Result<(Bytes, String), tonic::Status>,
+    chunk: Option<ChunkInfo>,
+) -> IFabricTransportMessage {
+    let mut replyheader = ReplyHeader {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        capabilities: protocol::CAPABILITIES,
+        ..Default::default()
+    };
+    let mut replybody = Bytes::new();
+    match payload {
+        Err(st) => {
+            replyheader.status_code = st.code() as i32;
+            replyheader.status_message = String::from(st.message());
+        }
+        Ok((content, encoding)) => {
+            replyheader.status_code = tonic::Code::Ok as i32;
+            replyheader.status_message = String::from("Ok");
+            replyheader.encoding = encoding;
+            replyheader.chunk = chunk;
+            replybody = content;
+        }
+    }
+
+    let header_buff = encode_proto(&replyheader).unwrap();
+    crate::sys::Message::create(header_buff, replybody)
+}
+
 // parse proto from bytes
 pub fn parse_proto<T: prost::Message + Default>(buf: &[u8]) -> Result<T, tonic::Status> {
     let proto = T::decode(buf);
@@ -182,24 +980,44 @@ pub fn parse_proto<T: prost::Message + Default>(buf: &[u8]) -> Result<T, tonic::
     Ok(proto.unwrap())
 }
 
-pub fn encode_proto<T: prost::Message>(proto: &T) -> Result<Vec<u8>, tonic::Status> {
-    let mut buf = Vec::new();
-    let err = proto.encode(&mut buf);
-    if let Err(e) = err {
-        let mut err_str = String::from("failed to encode proto: ");
-        err_str.push_str(&e.to_string());
-        return Err(tonic::Status::internal(err_str));
+pub fn encode_proto<T: prost::Message>(proto: &T) -> Result<Bytes, tonic::Status> {
+    Ok(buffer::encode(proto))
+}
+
+// Interceptor runs before dispatch with the request context.
+// It can reject the request, e.g. for auth or per client rate limiting,
+// or add metadata for the handler.
+pub trait Interceptor: Send + Sync {
+    fn call(&self, ctx: &mut RequestContext) -> Result<(), tonic::Status>;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(&mut RequestContext) -> Result<(), tonic::Status> + Send + Sync,
+{
+    fn call(&self, ctx: &mut RequestContext) -> Result<(), tonic::Status> {
+        self(ctx)
     }
-    Ok(buf)
 }
 
 // Each rpc service needs to implement this
 #[async_trait]
 pub trait Service: Send + Sync {
     fn name(&self) -> String;
+    // static description of the methods, emitted by fabric-rpc-build
+    fn descriptor(&self) -> Option<&'static ServiceDescriptor> {
+        None
+    }
+    // codec of request and reply bodies, requests in other content types
+    // are rejected with Unimplemented
+    fn content_type(&self) -> &'static str {
+        codec::PROTOBUF
+    }
+    // called when the server shutdown signal fires
+    fn on_shutdown(&self) {}
     async fn handle_request(
         &self,
         url: String,
-        request: &[u8],
-    ) -> std::result::Result<Vec<u8>, tonic::Status>;
+        request: Bytes,
+    ) -> std::result::Result<Bytes, tonic::Status>;
 }
@@ -1,7 +1,4 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::{sync::Arc, time::Instant};
 
 use fabric_base::{
     FabricCommon::{
@@ -18,13 +15,14 @@ use fabric_base::{
     FABRIC_E_NOT_READY,
 };
 use tokio::sync::mpsc::{self, Receiver, Sender};
-//use tokio::sync::Mutex;
 use windows::{
     core::{implement, ComInterface, Error, HRESULT, HSTRING},
-    Win32::Foundation::E_POINTER,
+    Win32::Foundation::{E_INVALIDARG, E_POINTER},
 };
 
 use crate::{
+    conn_table::{self, ConnectionTable},
+    metrics::MetricsRecorder,
     shared_tr::MsgDispoer,
     sys::{raw_to_hstring, AwaitableCallback, Context, ContextWrapper, StringViewer},
 };
@@ -33,23 +31,13 @@ use crate::{
 //#[derive(Debug)]
 #[implement(IFabricTransportConnectionHandler)]
 struct ServerConnHandler {
-    internal: Arc<ServerInternal>, // need to use cvoid because there is no way to anotate lifetime for implements
+    internal: Arc<ServerInternal>,
 }
 
 impl ServerConnHandler {
     pub fn new(internal: Arc<ServerInternal>) -> ServerConnHandler {
         ServerConnHandler { internal }
     }
-
-    // TODO: maybe fix this using refcell etc
-    #[allow(clippy::mut_from_ref)]
-    fn get_internal_mut(&self) -> &mut ServerInternal {
-        // get a view of the Arc
-        // need unsafe since to work around Arc immutable view
-        let ptr: *const ServerInternal = std::ptr::addr_of!(*self.internal);
-        let ptr_mut = ptr as *mut ServerInternal;
-        unsafe { &mut *ptr_mut }
-    }
 }
 
 #[allow(non_snake_case)]
@@ -67,7 +55,10 @@ impl IFabricTransportConnectionHandler_Impl for ServerConnHandler {
         let cb = callback.unwrap();
         // push the connection
         let client = clientconnection.unwrap();
-        self.get_internal_mut().push(client.clone())?;
+        if let Err(e) = self.internal.push(client.clone()) {
+            tracing::warn!(error = %e.message(), "connection rejected");
+            return Err(e);
+        }
 
         let mut ctx = Context::new(cb.clone());
         ctx.complete();
@@ -101,7 +92,11 @@ impl IFabricTransportConnectionHandler_Impl for ServerConnHandler {
         let cb = callback.unwrap();
         let id = raw_to_hstring(clientid);
 
-        self.get_internal_mut().disconnect(id);
+        tracing::debug!(client_id = %id, "client disconnected");
+        if let Err(e) = self.internal.disconnect(&id) {
+            tracing::warn!(error = %e.message(), "disconnect failed");
+            return Err(e);
+        }
 
         let mut ctx = Context::new(cb.clone());
         ctx.complete();
@@ -134,14 +129,6 @@ impl MessageHandler {
     pub fn new(internal: Arc<ServerInternal>) -> MessageHandler {
         MessageHandler { internal }
     }
-
-    // this is to satisfy the com implements functions are immutable
-    #[allow(clippy::mut_from_ref)]
-    fn get_internal_mut(&self) -> &mut ServerInternal {
-        let ptr: *const ServerInternal = std::ptr::addr_of!(*self.internal);
-        let ptr_mut = ptr as *mut ServerInternal;
-        unsafe { &mut *ptr_mut }
-    }
 }
 
 #[allow(non_snake_case)]
@@ -150,10 +137,9 @@ impl IFabricTransportMessageHandler_Impl for MessageHandler {
         &self,
         clientid: *const u16,
         message: ::core::option::Option<&IFabricTransportMessage>,
-        _timeoutmilliseconds: u32,
+        timeoutmilliseconds: u32,
         callback: ::core::option::Option<&IFabricAsyncOperationCallback>,
     ) -> ::windows::core::Result<IFabricAsyncOperationContext> {
-        // println!("Server Transport begin process request");
         if message.is_none() || callback.is_none() {
             return Err(E_POINTER.into());
         }
@@ -164,13 +150,22 @@ impl IFabricTransportMessageHandler_Impl for MessageHandler {
         let ctx = Context::new(cb.clone());
 
         let id = raw_to_hstring(clientid);
-        let req = ServerRequest {
-            msg: msg.clone(),
-            ctx: ctx.clone(),
-        };
-        self.get_internal_mut()
-            .push_requst(id, req)
-            .expect("push request failed");
+        let req = ServerRequest::new(id.clone(), msg.clone(), timeoutmilliseconds, ctx.clone());
+        match self.internal.push_requst(&id, req) {
+            Ok(()) => {}
+            // reject right away instead of blocking the transport thread
+            Err(PushError::Full(mut req)) => {
+                tracing::warn!(client_id = %id, "connection queue is full, request rejected");
+                match self.internal.busy_reply.as_ref() {
+                    Some(busy_reply) => req.complete(busy_reply()),
+                    None => return Err(not_ready("connection queue is full")),
+                }
+            }
+            Err(PushError::Failed(e)) => {
+                tracing::warn!(client_id = %id, error = %e.message(), "request rejected");
+                return Err(e);
+            }
+        }
 
         Ok(ctx.into())
     }
@@ -179,7 +174,6 @@ impl IFabricTransportMessageHandler_Impl for MessageHandler {
         &self,
         context: ::core::option::Option<&IFabricAsyncOperationContext>,
     ) -> ::windows::core::Result<IFabricTransportMessage> {
-        //println!("Server Transport end process request");
         if context.is_none() {
             return Err(E_POINTER.into());
         }
@@ -192,137 +186,255 @@ impl IFabricTransportMessageHandler_Impl for MessageHandler {
 
     fn HandleOneWay(
         &self,
-        _clientid: *const u16,
-        _message: ::core::option::Option<&IFabricTransportMessage>,
+        clientid: *const u16,
+        message: ::core::option::Option<&IFabricTransportMessage>,
     ) -> ::windows::core::Result<()> {
-        Ok(())
+        if message.is_none() {
+            return Err(E_POINTER.into());
+        }
+
+        let id = raw_to_hstring(clientid);
+        let req = ServerRequest::new_one_way(id.clone(), message.unwrap().clone());
+        match self.internal.push_requst(&id, req) {
+            Ok(()) => Ok(()),
+            // nobody to reply to, the msg is dropped
+            Err(PushError::Full(_)) => {
+                tracing::warn!(client_id = %id, "connection queue is full, one-way msg dropped");
+                Err(not_ready("connection queue is full"))
+            }
+            Err(PushError::Failed(e)) => {
+                tracing::warn!(client_id = %id, error = %e.message(), "one-way msg dropped");
+                Err(e)
+            }
+        }
     }
 }
 
-// TODO: split the server connection into internal entry and reveiver end.
-// server internal. keeps track of connections
-#[derive(Debug)]
-struct ServerInternal {
-    conns: Mutex<HashMap<String, ServerConnectionInternal>>,
-    rx: Receiver<ServerConnection>,
-    tx: Sender<ServerConnection>,
+// builds the reply for requests rejected when the connection queue is full
+pub type BusyReply = Arc<dyn Fn() -> IFabricTransportMessage + Send + Sync>;
+
+// transport level limits
+#[derive(Clone)]
+pub struct ServerTransportOptions {
+    // max requests queued per connection, more are rejected
+    pub connection_queue_size: usize,
+    // reply for rejected requests. If none the request fails with an HRESULT.
+    pub busy_reply: Option<BusyReply>,
+    // told when connections open or close
+    pub metrics: Option<Arc<dyn MetricsRecorder>>,
 }
 
-unsafe impl Send for ServerInternal {}
-unsafe impl Sync for ServerInternal {}
-
-impl Default for ServerInternal {
+impl Default for ServerTransportOptions {
     fn default() -> Self {
-        Self::new()
+        ServerTransportOptions {
+            connection_queue_size: 100,
+            busy_reply: None,
+            metrics: None,
+        }
     }
 }
 
+// why a request could not be queued
+enum PushError {
+    // queue is full, request is handed back to be rejected
+    Full(ServerRequest),
+    Failed(Error),
+}
+
+// server internal. keeps track of connections.
+// Shared by the COM handlers which may be called from any transport thread,
+// so everything goes through &self.
+struct ServerInternal {
+    conns: ConnectionTable<Sender<ServerRequest>>,
+    tx: Sender<ServerConnection>,
+    busy_reply: Option<BusyReply>,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+}
+
 impl ServerInternal {
-    pub fn new() -> ServerInternal {
+    // returns the receiving end of accepted connections
+    pub fn new(options: ServerTransportOptions) -> (ServerInternal, Receiver<ServerConnection>) {
         let (tx, rx) = mpsc::channel::<ServerConnection>(100);
-        ServerInternal {
-            conns: Mutex::new(HashMap::new()),
+        let internal = ServerInternal {
+            conns: ConnectionTable::new(options.connection_queue_size),
             tx,
-            rx,
-        }
+            busy_reply: options.busy_reply,
+            metrics: options.metrics,
+        };
+        (internal, rx)
     }
 
     // add a connection
-    pub fn push(&mut self, client: IFabricTransportClientConnection) -> Result<(), Error> {
-        let id_raw = unsafe { client.get_ClientId() };
-        // hstring does not have hash impl
-        let id = raw_to_hstring(id_raw).to_string();
-
-        let (tx, rx) = tokio::sync::mpsc::channel::<ServerRequest>(100);
+    pub fn push(&self, client: IFabricTransportClientConnection) -> Result<(), Error> {
+        let id = raw_to_hstring(unsafe { client.get_ClientId() });
+        let rx = self.register(&id)?;
         let conn = ServerConnection::new(client, rx);
-        let conn_internal = ServerConnectionInternal::new(tx);
-
-        let res = self.tx.blocking_send(conn);
-        if res.is_err() {
-            // TODO: remove connection?
-            let err = res.err().unwrap();
-            let ret_err = Error::new(
-                HRESULT(FABRIC_E_NOT_READY.0),
-                HSTRING::from(err.to_string()),
-            );
-            return Err(ret_err);
-        }
 
-        self.conns.lock().unwrap().insert(id, conn_internal);
+        // do not block the transport thread if the server is not accepting
+        if let Err(e) = self.tx.try_send(conn) {
+            let _ = self.conns.remove(&id.to_string());
+            return Err(not_ready(&e.to_string()));
+        }
+        tracing::debug!(client_id = %id, "client connected");
+        self.record_connections();
         Ok(())
     }
 
-    pub async fn async_pop(&mut self) -> ServerConnection {
-        self.rx.recv().await.unwrap()
+    // create the request queue of a new connection
+    fn register(&self, id: &HSTRING) -> Result<Receiver<ServerRequest>, Error> {
+        // hstring does not have hash impl
+        self.conns.register(&id.to_string()).map_err(|_| {
+            Error::new(
+                E_INVALIDARG,
+                HSTRING::from(format!("connection {} already exists", id)),
+            )
+        })
     }
 
     // disconnect one client
-    pub fn disconnect(&mut self, id: HSTRING) {
-        let val = self.conns.lock().unwrap().remove(&id.to_string());
-        if let Some(mut vv) = val {
-            vv.disconnected = true;
-        } else {
-            panic!("disconnect of non exist connection");
+    // dropping the sender ends the connection once its queue is drained
+    pub fn disconnect(&self, id: &HSTRING) -> Result<(), Error> {
+        self.conns
+            .remove(&id.to_string())
+            .map_err(|_| unknown_connection(id))?;
+        self.record_connections();
+        Ok(())
+    }
+
+    fn record_connections(&self) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            // under the lock so the recorder sees counts in order
+            self.conns.with_len(|n| metrics.connections_changed(n));
         }
     }
 
     // push a msg to a connection
-    pub fn push_requst(&mut self, id: HSTRING, req: ServerRequest) -> Result<(), Error> {
-        // println!("Pushing request {}", id);
-        let cc = self.conns.lock().unwrap();
-        let val = cc.get(&id.to_string());
-        if let Some(vv) = val {
-            vv.push(req)?;
-        } else {
-            panic!("request pushed to unknown connection");
-        }
-        Ok(())
+    fn push_requst(&self, id: &HSTRING, req: ServerRequest) -> Result<(), PushError> {
+        // transport can sync push into the queue without blocking
+        self.conns.push(&id.to_string(), req).map_err(|e| match e {
+            conn_table::PushError::Full(req) => PushError::Full(req),
+            conn_table::PushError::Closed => PushError::Failed(not_ready("connection is closed")),
+            conn_table::PushError::Unknown => PushError::Failed(unknown_connection(id)),
+        })
     }
 }
 
+fn not_ready(msg: &str) -> Error {
+    Error::new(HRESULT(FABRIC_E_NOT_READY.0), HSTRING::from(msg))
+}
+
+fn unknown_connection(id: &HSTRING) -> Error {
+    Error::new(
+        E_INVALIDARG,
+        HSTRING::from(format!("unknown connection {}", id)),
+    )
+}
+
 #[derive(Debug)]
 // request item that server needs to process
 pub struct ServerRequest {
+    client_id: HSTRING,
     msg: IFabricTransportMessage,
-    ctx: Context, // context returned to FabricTransport
+    timeout_milliseconds: u32,
+    received_at: Instant,
+    ctx: Option<Context>, // context returned to FabricTransport, none for one-way
 }
 
 unsafe impl Send for ServerRequest {}
 unsafe impl Sync for ServerRequest {}
 
 impl ServerRequest {
-    pub fn new(msg: IFabricTransportMessage, ctx: Context) -> ServerRequest {
-        ServerRequest { msg, ctx }
+    pub fn new(
+        client_id: HSTRING,
+        msg: IFabricTransportMessage,
+        timeout_milliseconds: u32,
+        ctx: Context,
+    ) -> ServerRequest {
+        ServerRequest {
+            client_id,
+            msg,
+            timeout_milliseconds,
+            received_at: Instant::now(),
+            ctx: Some(ctx),
+        }
+    }
+
+    // request sent with no reply expected
+    pub fn new_one_way(client_id: HSTRING, msg: IFabricTransportMessage) -> ServerRequest {
+        ServerRequest {
+            client_id,
+            msg,
+            // INFINITE
+            timeout_milliseconds: u32::MAX,
+            received_at: Instant::now(),
+            ctx: None,
+        }
+    }
+
+    pub fn is_one_way(&self) -> bool {
+        self.ctx.is_none()
     }
 
+    // send the reply. No-op for one-way requests.
     pub fn complete(&mut self, reply: IFabricTransportMessage) {
-        self.ctx.set_msg(reply);
-        self.ctx.complete();
+        let ctx = match self.ctx.as_mut() {
+            Some(ctx) => ctx,
+            None => return,
+        };
+        ctx.set_msg(reply);
+        ctx.complete();
 
         // notify the reply is ready
-        let cb = self.ctx.Callback().unwrap();
-        unsafe {
-            cb.Invoke(&Into::<IFabricAsyncOperationContext>::into(
-                self.ctx.clone(),
-            ))
-        };
+        let cb = ctx.Callback().unwrap();
+        unsafe { cb.Invoke(&Into::<IFabricAsyncOperationContext>::into(ctx.clone())) };
     }
 
     pub fn get_request_msg(&self) -> &IFabricTransportMessage {
         &self.msg
     }
+
+    pub fn client_id(&self) -> &HSTRING {
+        &self.client_id
+    }
+
+    // timeout the transport received with the request
+    pub fn timeout_milliseconds(&self) -> u32 {
+        self.timeout_milliseconds
+    }
+
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+}
+
+// handle to send one-way msgs to a connected client
+#[derive(Debug, Clone)]
+pub struct ClientConnection {
+    c: IFabricTransportClientConnection,
+}
+
+// fabric connection is thread safe
+unsafe impl Send for ClientConnection {}
+unsafe impl Sync for ClientConnection {}
+
+impl ClientConnection {
+    pub fn client_id(&self) -> HSTRING {
+        raw_to_hstring(unsafe { self.c.get_ClientId() })
+    }
+
+    // client receives it in its one-way handler
+    pub fn send(&self, msg: &IFabricTransportMessage) -> Result<(), Error> {
+        unsafe { self.c.Send(msg) }
+    }
 }
 
 #[derive(Debug)]
 pub struct ServerConnection {
     rx: Receiver<ServerRequest>,
     // can be used to send back msg
-    _client: IFabricTransportClientConnection,
+    client: ClientConnection,
 }
 
-unsafe impl Send for ServerConnection {}
-unsafe impl Sync for ServerConnection {}
-
 impl ServerConnection {
     pub fn new(
         client: IFabricTransportClientConnection,
@@ -330,10 +442,14 @@ impl ServerConnection {
     ) -> ServerConnection {
         ServerConnection {
             rx,
-            _client: client,
+            client: ClientConnection { c: client },
         }
     }
 
+    pub fn client(&self) -> &ClientConnection {
+        &self.client
+    }
+
     pub async fn async_accept(&mut self) -> Option<ServerRequest> {
         // if rx is not closed there is always item to pop
         // if returned request is none, it means that the connection is dropped
@@ -341,61 +457,33 @@ impl ServerConnection {
     }
 }
 
-// internal book keeping for server connection
-#[derive(Debug)]
-struct ServerConnectionInternal {
-    tx: Sender<ServerRequest>,
-    disconnected: bool, // TODO: share this with public
-}
-
-impl ServerConnectionInternal {
-    fn new(tx: Sender<ServerRequest>) -> ServerConnectionInternal {
-        ServerConnectionInternal {
-            tx,
-            disconnected: false,
-        }
-    }
-
-    // transport can sync push into the queue
-    pub fn push(&self, req: ServerRequest) -> Result<(), Error> {
-        let res = self.tx.blocking_send(req); // channel is closed???
-        assert!(res.is_ok(), "{}", res.unwrap_err().to_string());
-        if res.is_ok() {
-            Ok(())
-        } else {
-            let err = res.err().unwrap();
-            let ret_err = Error::new(
-                HRESULT(FABRIC_E_NOT_READY.0),
-                HSTRING::from(err.to_string()),
-            );
-            Err(ret_err)
-        }
-    }
-}
-
 // server
 pub struct ServerTransport {
     l: IFabricTransportListener,
-    internal: Arc<ServerInternal>,
+    // connections accepted by the connection handler
+    rx: Receiver<ServerConnection>,
+    // keeps the sender alive so rx is never closed
+    _internal: Arc<ServerInternal>,
 }
 
 unsafe impl Send for ServerTransport {}
 unsafe impl Sync for ServerTransport {}
 
-// Note that ServerInternal object shared between ConnectionHandler
-// and MessageHandler are terribly unsafe.
-// We use a Arc to do refcount, but need to modify the contents of ServerInternal.
-// So the unsafe code need to obtain the mutable ref.
-// This difficulty is reached because ConnectionHandler and MessageHanlder needs to share
-// the ServerInternal object, and use async methods. If we use Arc<Mutex<>>, we need to
-// hold the mutex while doing await, which can cause more deadlocks.
-// So we share the mutable object use Arc and ServerInternal object itself is threadsafe.
 impl ServerTransport {
     pub fn new(
         settings: &FABRIC_TRANSPORT_SETTINGS,
         address: &FABRIC_TRANSPORT_LISTEN_ADDRESS,
     ) -> Result<ServerTransport, Error> {
-        let internal = Arc::new(ServerInternal::new());
+        Self::new_with_options(settings, address, ServerTransportOptions::default())
+    }
+
+    pub fn new_with_options(
+        settings: &FABRIC_TRANSPORT_SETTINGS,
+        address: &FABRIC_TRANSPORT_LISTEN_ADDRESS,
+        options: ServerTransportOptions,
+    ) -> Result<ServerTransport, Error> {
+        let (internal, rx) = ServerInternal::new(options);
+        let internal = Arc::new(internal);
 
         let disposeprocessor: IFabricTransportMessageDisposer = MsgDispoer::new().into();
         let svr_conn_handler: IFabricTransportConnectionHandler =
@@ -414,7 +502,8 @@ impl ServerTransport {
         };
         Ok(ServerTransport {
             l: listener,
-            internal,
+            rx,
+            _internal: internal,
         })
     }
 
@@ -449,12 +538,113 @@ impl ServerTransport {
     }
 
     pub async fn async_accept(&mut self) -> ServerConnection {
-        let internal_ref;
-        {
-            let ptr: *const ServerInternal = std::ptr::addr_of!(*self.internal);
-            let ptr_mut = ptr as *mut ServerInternal;
-            internal_ref = unsafe { &mut *ptr_mut };
+        self.rx.recv().await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use tokio::sync::mpsc::error::TryRecvError;
+    use windows::{core::HSTRING, Win32::Foundation::E_INVALIDARG};
+
+    use crate::sys::{AwaitableCallback, Context, Message};
+
+    use super::{PushError, ServerInternal, ServerRequest, ServerTransportOptions};
+
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 200;
+
+    fn request(id: &HSTRING) -> ServerRequest {
+        let (callback, _) = AwaitableCallback::create();
+        let msg = Message::create(b"header".to_vec(), b"body".to_vec());
+        ServerRequest::new(id.clone(), msg, 1000, Context::new(callback))
+    }
+
+    fn client_id(t: usize, i: usize) -> HSTRING {
+        HSTRING::from(format!("client-{}-{}", t, i))
+    }
+
+    // threads connect, push and disconnect concurrently like transport threads do,
+    // each also racing requests and disconnects against its neighbour's connection.
+    // The interleavings of the bookkeeping are model checked in conn_table.
+    #[test]
+    fn stress_test() {
+        let options = ServerTransportOptions {
+            connection_queue_size: 4,
+            ..Default::default()
+        };
+        let (internal, _rx) = ServerInternal::new(options);
+        let internal = Arc::new(internal);
+        let disconnects = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let internal = internal.clone();
+                let disconnects = disconnects.clone();
+                thread::spawn(move || {
+                    for i in 0..ROUNDS {
+                        let id = client_id(t, i);
+                        let mut rx = internal.register(&id).unwrap();
+
+                        // full queues hand the request back, the neighbour may
+                        // have disconnected us already
+                        for _ in 0..6 {
+                            match internal.push_requst(&id, request(&id)) {
+                                Ok(()) | Err(PushError::Full(_)) => {}
+                                Err(PushError::Failed(e)) => assert_eq!(E_INVALIDARG, e.code()),
+                            }
+                        }
+
+                        // neighbour may not be connected yet or already gone
+                        let other = client_id((t + 1) % THREADS, i);
+                        let _ = internal.push_requst(&other, request(&other));
+                        if internal.disconnect(&other).is_ok() {
+                            disconnects.fetch_add(1, Ordering::SeqCst);
+                        }
+                        if internal.disconnect(&id).is_ok() {
+                            disconnects.fetch_add(1, Ordering::SeqCst);
+                        }
+
+                        // gone for good, late requests are rejected
+                        match internal.push_requst(&id, request(&id)) {
+                            Err(PushError::Failed(e)) => assert_eq!(E_INVALIDARG, e.code()),
+                            _ => panic!("push to disconnected client"),
+                        }
+
+                        // queued requests are still delivered, then the queue closes
+                        let mut delivered = 0;
+                        loop {
+                            match rx.try_recv() {
+                                Ok(_) => delivered += 1,
+                                Err(TryRecvError::Disconnected) => break,
+                                Err(TryRecvError::Empty) => panic!("queue left open"),
+                            }
+                        }
+                        assert!(delivered <= 4);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
         }
-        internal_ref.async_pop().await
+
+        // every connection was removed exactly once
+        assert_eq!(THREADS * ROUNDS, disconnects.load(Ordering::SeqCst));
+        assert_eq!(0, internal.conns.with_len(|n| n));
+        assert!(internal.disconnect(&client_id(0, 0)).is_err());
+
+        let id = client_id(0, 0);
+        let _rx = internal.register(&id).unwrap();
+        assert!(internal.register(&id).is_err());
+        internal.disconnect(&id).unwrap();
     }
 }
@@ -1,6 +1,8 @@
 // low level constructs for SF
 
-use std::cell::RefCell;
+use std::{borrow::Cow, cell::RefCell, sync::Arc};
+
+use bytes::Bytes;
 
 use fabric_base::FabricCommon::{
     FabricTransport::{
@@ -13,6 +15,8 @@ use fabric_base::FabricCommon::{
 use tokio::sync::oneshot::{self, Receiver, Sender};
 use windows::core::{implement, AsImpl, HSTRING, PCWSTR};
 
+use crate::buffer::MessageBody;
+
 #[allow(non_snake_case)]
 // awaitable callback is used to await a signal from fabric API.
 #[implement(IFabricAsyncOperationCallback)]
@@ -144,33 +148,42 @@ impl ContextWrapper {
 
 #[implement(IFabricTransportMessage)]
 pub struct Message {
-    header: Vec<u8>,
-    body: Vec<u8>,
+    header: Bytes,
+    body: Vec<Bytes>,
     header_buff: FABRIC_TRANSPORT_MESSAGE_BUFFER,
-    body_buff: FABRIC_TRANSPORT_MESSAGE_BUFFER,
+    body_buffs: Vec<FABRIC_TRANSPORT_MESSAGE_BUFFER>,
 }
 
 impl Message {
-    pub fn create(header: Vec<u8>, body: Vec<u8>) -> IFabricTransportMessage {
+    // the msg shares the buffers, Vec<u8> also converts without a copy
+    pub fn create(header: impl Into<Bytes>, body: impl Into<Bytes>) -> IFabricTransportMessage {
+        Message::create_vectored(header, vec![body.into()])
+    }
+
+    // body sent as a list of buffers, without concatenating them
+    pub fn create_vectored(header: impl Into<Bytes>, body: Vec<Bytes>) -> IFabricTransportMessage {
         let mut msg = Message {
-            header,
+            header: header.into(),
             body,
             header_buff: Default::default(),
-            body_buff: Default::default(),
+            body_buffs: Vec::new(),
         };
 
-        let h = &msg.header;
-        msg.header_buff.BufferSize = h.len() as u32;
-        msg.header_buff.Buffer = h.as_ptr() as *mut u8;
-
-        let b = &msg.body;
-        msg.body_buff.BufferSize = b.len() as u32;
-        msg.body_buff.Buffer = b.as_ptr() as *mut u8;
+        // Bytes never moves its data, so the pointers stay valid
+        msg.header_buff = to_buffer(&msg.header);
+        msg.body_buffs = msg.body.iter().map(to_buffer).collect();
 
         msg.into()
     }
 }
 
+fn to_buffer(b: &Bytes) -> FABRIC_TRANSPORT_MESSAGE_BUFFER {
+    FABRIC_TRANSPORT_MESSAGE_BUFFER {
+        BufferSize: b.len() as u32,
+        Buffer: b.as_ptr() as *mut u8,
+    }
+}
+
 #[allow(non_snake_case)]
 #[allow(clippy::not_unsafe_ptr_arg_deref)] // public def does not have unsafe
 impl IFabricTransportMessage_Impl for Message {
@@ -186,69 +199,149 @@ impl IFabricTransportMessage_Impl for Message {
         unsafe {
             *headerbuffer =
                 std::ptr::addr_of!(self.header_buff) as *mut FABRIC_TRANSPORT_MESSAGE_BUFFER;
-            *msgbuffercount = 1;
-            *msgbuffers =
-                std::ptr::addr_of!(self.body_buff) as *mut FABRIC_TRANSPORT_MESSAGE_BUFFER;
+            *msgbuffercount = self.body_buffs.len() as u32;
+            *msgbuffers = self.body_buffs.as_ptr() as *mut FABRIC_TRANSPORT_MESSAGE_BUFFER;
         }
     }
 
     fn Dispose(&self) {}
 }
 
-// viewing the msg
-pub struct MessageViewer<'a> {
+// Owned view of a msg. Holds a ref on the msg, so the buffers stay valid
+// as long as the viewer or any Bytes taken from it.
+pub struct MessageViewer {
     _msg: IFabricTransportMessage,
-    header: &'a [u8],
-    body: &'a [u8],
+    header: RawBuffer,
+    body: Vec<RawBuffer>,
 }
 
-unsafe impl Sync for MessageViewer<'_> {}
-unsafe impl Send for MessageViewer<'_> {}
+// buffers of a msg are not changed after it is built
+unsafe impl Sync for MessageViewer {}
+unsafe impl Send for MessageViewer {}
 
-impl MessageViewer<'_> {
-    pub fn new(msg: IFabricTransportMessage) -> MessageViewer<'static> {
-        //MessageViewer{msg}
+impl MessageViewer {
+    pub fn new(msg: IFabricTransportMessage) -> MessageViewer {
         let mut header_buff: *mut FABRIC_TRANSPORT_MESSAGE_BUFFER = std::ptr::null_mut();
-        let mut body_buff: *mut FABRIC_TRANSPORT_MESSAGE_BUFFER = std::ptr::null_mut();
+        let mut body_buffs: *mut FABRIC_TRANSPORT_MESSAGE_BUFFER = std::ptr::null_mut();
         let mut body_count: u32 = 0;
 
         unsafe {
             msg.GetHeaderAndBodyBuffer(
                 std::ptr::addr_of_mut!(header_buff),
                 std::ptr::addr_of_mut!(body_count),
-                std::ptr::addr_of_mut!(body_buff),
+                std::ptr::addr_of_mut!(body_buffs),
             )
         };
-        let mut header_slice: &[u8] = &[];
-        let mut body_slice: &[u8] = &[];
-        if !header_buff.is_null() {
-            let header_ref = unsafe { &*header_buff };
-
-            header_slice = unsafe {
-                std::slice::from_raw_parts(header_ref.Buffer, header_ref.BufferSize as usize)
-            };
+        let header = RawBuffer::new(header_buff);
+        let body = if body_count != 0 && !body_buffs.is_null() {
+            // msgbuffers points to an array of body_count buffers
+            (0..body_count as usize)
+                .map(|i| RawBuffer::new(unsafe { body_buffs.add(i) }))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        MessageViewer {
+            _msg: msg,
+            header,
+            body,
         }
-        if body_count != 0 && !body_buff.is_null() {
-            let body_ref = unsafe { &*body_buff };
+    }
 
-            body_slice = unsafe {
-                std::slice::from_raw_parts(body_ref.Buffer, body_ref.BufferSize as usize)
-            };
+    pub fn get_header(&self) -> &[u8] {
+        // valid while self holds the msg
+        unsafe { self.header.as_slice() }
+    }
+
+    // whole body, concatenated if the peer sent several buffers
+    pub fn get_body(&self) -> Cow<'_, [u8]> {
+        match self.body.as_slice() {
+            [] => Cow::Borrowed(&[]),
+            [b] => Cow::Borrowed(unsafe { b.as_slice() }),
+            _ => Cow::Owned(self.get_body_buffers().flatten().copied().collect()),
         }
+    }
 
-        MessageViewer {
-            _msg: msg,
-            header: header_slice,
-            body: body_slice,
+    pub fn get_body_buffers(&self) -> impl Iterator<Item = &[u8]> {
+        self.body.iter().map(|b| unsafe { b.as_slice() })
+    }
+
+    pub fn body_len(&self) -> usize {
+        self.body.iter().map(|b| b.len).sum()
+    }
+
+    // header and body sharing the msg instead of copying it
+    pub fn into_parts(self) -> (Bytes, MessageBody) {
+        let vw = Arc::new(self);
+        let header = Bytes::from_owner(MessagePart {
+            vw: vw.clone(),
+            part: None,
+        });
+        let body = (0..vw.body.len()).map(|i| {
+            Bytes::from_owner(MessagePart {
+                vw: vw.clone(),
+                part: Some(i),
+            })
+        });
+        (header, MessageBody::new(body))
+    }
+
+    // like into_parts, with a body of several buffers copied into one
+    pub fn into_bytes(self) -> (Bytes, Bytes) {
+        let (header, body) = self.into_parts();
+        (header, body.into_bytes())
+    }
+}
+
+// buffer pointer handed out by the msg
+#[derive(Clone, Copy)]
+struct RawBuffer {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Default for RawBuffer {
+    fn default() -> Self {
+        RawBuffer {
+            ptr: std::ptr::null(),
+            len: 0,
         }
     }
+}
 
-    pub fn get_header(&self) -> &'_ [u8] {
-        self.header
+impl RawBuffer {
+    fn new(buff: *const FABRIC_TRANSPORT_MESSAGE_BUFFER) -> RawBuffer {
+        if buff.is_null() {
+            return RawBuffer::default();
+        }
+        let buff = unsafe { &*buff };
+        RawBuffer {
+            ptr: buff.Buffer,
+            len: buff.BufferSize as usize,
+        }
     }
 
-    pub fn get_body(&self) -> &'_ [u8] {
-        self.body
+    // caller keeps the msg owning the buffer alive
+    unsafe fn as_slice<'a>(&self) -> &'a [u8] {
+        if self.ptr.is_null() || self.len == 0 {
+            return &[];
+        }
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+// keeps the viewer alive for Bytes::from_owner, None is the header
+struct MessagePart {
+    vw: Arc<MessageViewer>,
+    part: Option<usize>,
+}
+
+impl AsRef<[u8]> for MessagePart {
+    fn as_ref(&self) -> &[u8] {
+        match self.part {
+            Some(i) => unsafe { self.vw.body[i].as_slice() },
+            None => self.vw.get_header(),
+        }
     }
 }
 
@@ -318,5 +411,34 @@ mod tests {
 
         let body_ret = msgvw.get_body();
         assert_eq!(body_ret, body.as_bytes());
+
+        // the bytes outlive the viewer
+        let (header_ret, body_ret) = msgvw.into_bytes();
+        assert_eq!(header_ret, header.as_bytes());
+        assert_eq!(body_ret, body.as_bytes());
+    }
+
+    #[test]
+    fn test_msg_vectored() {
+        let body = vec![
+            Bytes::from_static(b"my"),
+            Bytes::new(),
+            Bytes::from_static(b"body"),
+        ];
+        let msg = Message::create_vectored(Bytes::from_static(b"myheader"), body);
+
+        let msgvw = MessageViewer::new(msg);
+        assert_eq!(3, msgvw.get_body_buffers().count());
+        assert_eq!(msgvw.get_body(), &b"mybody"[..]);
+
+        let (header_ret, body_ret) = msgvw.into_parts();
+        assert_eq!(header_ret, &b"myheader"[..]);
+        // empty buffers are dropped from the chain
+        assert_eq!(2, body_ret.parts().count());
+        assert_eq!(body_ret.into_bytes(), &b"mybody"[..]);
+
+        let msgvw = MessageViewer::new(Message::create_vectored(Bytes::new(), Vec::new()));
+        assert_eq!(0, msgvw.get_body_buffers().count());
+        assert!(msgvw.get_body().is_empty());
     }
 }
@@ -88,7 +88,7 @@ async fn fabric_transport() {
                     let mut reply_header = hello.clone();
                     reply_header.extend(header);
                     let mut reply_body = hello;
-                    reply_body.extend(body);
+                    reply_body.extend_from_slice(&body);
 
                     let reply = Message::create(reply_header, reply_body);
                     req.complete(reply);
@@ -151,6 +151,7 @@ async fn fabric_transport() {
 #[cfg(test)]
 mod hello_test {
 
+    use bytes::Bytes;
     use windows::core::{Error, HSTRING};
 
     use crate::{
@@ -201,11 +202,11 @@ mod hello_test {
         async fn handle_request(
             &self,
             url: String,
-            request: &[u8],
-        ) -> std::result::Result<Vec<u8>, tonic::Status> {
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
             match url.as_str() {
                 "/helloworld.Greeter/SayHello" => {
-                    let req = parse_proto(request)?;
+                    let req = parse_proto(&request)?;
                     let resp = self.svc.say_hello(req).await?;
                     return encode_proto(&resp);
                 }
@@ -336,3 +337,1679 @@ mod test_grpc {
         tx.send(()).unwrap();
     }
 }
+
+#[cfg(test)]
+mod shutdown_test {
+    use std::{sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+    use tonic::{Code, Status};
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        metrics::{MemoryRecorder, Side},
+        server::{Server, Service, ShutdownSummary},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    // service that takes a while to reply
+    pub(super) struct SlowService {
+        pub(super) delay: Duration,
+    }
+
+    #[tonic::async_trait]
+    impl Service for SlowService {
+        fn name(&self) -> String {
+            String::from("test.Slow")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            _request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            tokio::time::sleep(self.delay).await;
+            Ok(Bytes::new())
+        }
+    }
+
+    // shut the server down while the requests are in flight
+    async fn run_shutdown(
+        port: u32,
+        calls: usize,
+        delay: Duration,
+        grace: Duration,
+    ) -> (
+        Vec<Result<HelloReply, Status>>,
+        ShutdownSummary,
+        Arc<MemoryRecorder>,
+    ) {
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let metrics = Arc::new(MemoryRecorder::default());
+
+        let server_metrics = metrics.clone();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            svr.grace_period(grace);
+            svr.metrics(server_metrics);
+            // the others wait in the queue
+            svr.connection_concurrency_limit(1);
+            svr.add_service(SlowService { delay });
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+
+        let request = HelloRequest::default();
+        let calls = (0..calls).map(|_| {
+            client.request::<HelloReply>(String::from("/test.Slow/slow"), &request, 10000)
+        });
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            stoptx.send(()).unwrap();
+        };
+        let (resps, _) = tokio::join!(futures::future::join_all(calls), stop);
+        (resps, server.await.unwrap(), metrics)
+    }
+
+    #[tokio::test]
+    async fn drain_test() {
+        let (resps, summary, _) =
+            run_shutdown(12352, 1, Duration::from_millis(500), Duration::from_secs(5)).await;
+        assert!(resps[0].is_ok());
+        assert_eq!(
+            ShutdownSummary {
+                drained: 1,
+                aborted: 0,
+                rejected: 0
+            },
+            summary
+        );
+    }
+
+    #[tokio::test]
+    async fn abort_test() {
+        let (resps, summary, metrics) =
+            run_shutdown(12353, 1, Duration::from_secs(5), Duration::from_millis(100)).await;
+        assert_eq!(Code::Unavailable, resps[0].as_ref().unwrap_err().code());
+        assert_eq!(0, summary.drained);
+        assert_eq!(1, summary.aborted);
+        // aborted calls leave the in-flight count too
+        let slow = metrics.method(Side::Server, "/test.Slow/slow");
+        assert_eq!(0, slow.in_flight);
+        assert_eq!(Some(&1), slow.codes.get(&Code::Unavailable));
+    }
+
+    // requests queued behind the connection limit count as in flight
+    #[tokio::test]
+    async fn queued_abort_test() {
+        let (resps, summary, _) =
+            run_shutdown(12381, 3, Duration::from_secs(5), Duration::from_millis(100)).await;
+        for resp in resps {
+            assert_eq!(Code::Unavailable, resp.unwrap_err().code());
+        }
+        assert_eq!(
+            ShutdownSummary {
+                drained: 0,
+                aborted: 3,
+                rejected: 0
+            },
+            summary
+        );
+    }
+}
+
+#[cfg(test)]
+mod limit_test {
+    use std::time::Duration;
+
+    use tonic::{Code, Status};
+    use windows::core::HSTRING;
+
+    use crate::{client::Client2, server::Server};
+
+    use super::{
+        shutdown_test::SlowService,
+        test_grpc::hello_world::{HelloReply, HelloRequest},
+    };
+
+    const SLOW_URL: &str = "/test.Slow/slow";
+
+    async fn call(client: &Client2) -> Result<HelloReply, Status> {
+        let request = HelloRequest::default();
+        client
+            .request::<HelloReply>(String::from(SLOW_URL), &request, 5000)
+            .await
+    }
+
+    fn count_exhausted(resps: &[&Result<HelloReply, Status>]) -> usize {
+        resps
+            .iter()
+            .filter(|r| matches!(r, Err(st) if st.code() == Code::ResourceExhausted))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn method_limit_test() {
+        let port = 12354;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            svr.method_concurrency_limit(SLOW_URL, 1);
+            svr.add_service(SlowService {
+                delay: Duration::from_millis(500),
+            });
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client1 = Client2::connect(connectionaddress.clone()).await.unwrap();
+        let client2 = Client2::connect(connectionaddress).await.unwrap();
+
+        // two connections hit the same method, one gets shed
+        let (r1, r2) = tokio::join!(call(&client1), call(&client2));
+        assert_eq!(1, count_exhausted(&[&r1, &r2]));
+        assert!(r1.is_ok() || r2.is_ok());
+
+        // permit is released after the call
+        assert!(call(&client1).await.is_ok());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queue_full_test() {
+        let port = 12355;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            svr.connection_queue_size(1).connection_concurrency_limit(1);
+            svr.add_service(SlowService {
+                delay: Duration::from_millis(500),
+            });
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+
+        // one request running, one queued, the rest are rejected right away
+        let (r1, r2, r3) = tokio::join!(call(&client), call(&client), call(&client));
+        assert!(count_exhausted(&[&r1, &r2, &r3]) >= 1);
+        assert!(r1.is_ok());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod concurrency_test {
+    use std::time::{Duration, Instant};
+
+    use windows::core::HSTRING;
+
+    use crate::{client::Client2, server::Server};
+
+    use super::{
+        shutdown_test::SlowService,
+        test_grpc::hello_world::{HelloReply, HelloRequest},
+    };
+
+    const SLOW_URL: &str = "/test.Slow/slow";
+    const DELAY: Duration = Duration::from_millis(500);
+
+    // time two calls on the same connection
+    async fn run_two_calls(port: u32, ordered: bool) -> Duration {
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            if ordered {
+                svr.preserve_order(SLOW_URL);
+            }
+            svr.add_service(SlowService { delay: DELAY });
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+
+        let request = HelloRequest::default();
+        let start = Instant::now();
+        let (r1, r2) = tokio::join!(
+            client.request::<HelloReply>(String::from(SLOW_URL), &request, 5000),
+            client.request::<HelloReply>(String::from(SLOW_URL), &request, 5000)
+        );
+        let elapsed = start.elapsed();
+        assert!(r1.is_ok());
+        assert!(r2.is_ok());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+        elapsed
+    }
+
+    #[tokio::test]
+    async fn concurrent_test() {
+        // slow calls from one client overlap
+        let elapsed = run_two_calls(12356, false).await;
+        assert!(elapsed < DELAY * 2, "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn ordered_test() {
+        let elapsed = run_two_calls(12357, true).await;
+        assert!(elapsed >= DELAY * 2, "{:?}", elapsed);
+    }
+}
+
+#[cfg(test)]
+mod context_test {
+    use bytes::Bytes;
+    use tonic::{Code, Status};
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::{unary, Client2, InterceptedChannel},
+        context::RequestContext,
+        fabricrpc_header::RequestHeader,
+        server::{encode_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const WHO_URL: &str = "/test.Ctx/who";
+
+    // replies with what it sees in the request context
+    struct CtxService {}
+
+    #[tonic::async_trait]
+    impl Service for CtxService {
+        fn name(&self) -> String {
+            String::from("test.Ctx")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            _request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let ctx = RequestContext::current().unwrap();
+            assert_eq!(WHO_URL, ctx.url());
+            assert!(!ctx.client_id().is_empty());
+            assert!(ctx.deadline().is_some());
+            let user = ctx.metadata().get("user").cloned().unwrap_or_default();
+            encode_proto(&HelloReply { message: user })
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_test() {
+        let port = 12358;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            svr.add_interceptor(|ctx: &mut RequestContext| {
+                if !ctx.metadata().contains_key("user") {
+                    return Err(Status::unauthenticated("no user"));
+                }
+                Ok(())
+            });
+            svr.add_service(CtxService {});
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let request = HelloRequest::default();
+
+        // rejected by the server interceptor
+        let err = unary::<_, HelloReply>(&client, String::from(WHO_URL), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::Unauthenticated, err.code());
+
+        let channel = InterceptedChannel::new(client, |mut header: RequestHeader| {
+            header
+                .metadata
+                .insert(String::from("user"), String::from("alice"));
+            Ok(header)
+        });
+        let reply = unary::<_, HelloReply>(&channel, String::from(WHO_URL), &request, 5000)
+            .await
+            .unwrap();
+        assert_eq!("alice", reply.message);
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod push_test {
+    use std::time::Duration;
+
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+    use windows::core::HSTRING;
+
+    use crate::{client::Client2, server::Server};
+
+    use super::test_grpc::hello_world::HelloReply;
+
+    const EVENT_URL: &str = "/test.Push/event";
+
+    #[tokio::test]
+    async fn notify_test() {
+        let port = 12359;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let svr = Server::default();
+        let notifier = svr.notifier();
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let mut events = Box::pin(client.subscribe::<HelloReply>(EVENT_URL));
+        let mut others = Box::pin(client.subscribe::<HelloReply>("/test.Push/other"));
+
+        // wait for the server to register the connection
+        while notifier.client_ids().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let result = notifier
+            .broadcast(
+                EVENT_URL,
+                &HelloReply {
+                    message: String::from("all"),
+                },
+            )
+            .unwrap();
+        assert_eq!(1, result.sent);
+        assert!(result.failed.is_empty());
+        let id = notifier.client_ids().pop().unwrap();
+        notifier
+            .notify(
+                &id,
+                EVENT_URL,
+                &HelloReply {
+                    message: String::from("one"),
+                },
+            )
+            .unwrap();
+
+        assert_eq!("all", events.next().await.unwrap().unwrap().message);
+        assert_eq!("one", events.next().await.unwrap().unwrap().message);
+        // other urls are filtered out
+        let other = tokio::time::timeout(Duration::from_millis(100), others.next()).await;
+        assert!(other.is_err());
+
+        let err = notifier
+            .notify("not-a-client", EVENT_URL, &HelloReply::default())
+            .unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+        assert!(notifier.client_ids().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod one_way_test {
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        context::RequestContext,
+        server::{parse_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::HelloRequest;
+
+    // forwards what it receives to the test
+    struct SinkService {
+        tx: mpsc::Sender<(String, bool)>,
+    }
+
+    #[tonic::async_trait]
+    impl Service for SinkService {
+        fn name(&self) -> String {
+            String::from("test.Sink")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let req: HelloRequest = parse_proto(&request)?;
+            let one_way = RequestContext::current().unwrap().is_one_way();
+            self.tx.send((req.name, one_way)).await.unwrap();
+            Ok(Bytes::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn send_one_way_test() {
+        let port = 12362;
+        let (tx, mut rx) = mpsc::channel(10);
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            svr.add_service(SinkService { tx });
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+
+        let request = HelloRequest {
+            name: String::from("fire"),
+        };
+        client
+            .send_one_way(String::from("/test.Sink/sink"), &request)
+            .await
+            .unwrap();
+        assert_eq!((String::from("fire"), true), rx.recv().await.unwrap());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod lifecycle_test {
+    use bytes::Bytes;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        connection::ConnectionEvent,
+        context::RequestContext,
+        server::{encode_proto, parse_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    #[derive(Clone)]
+    struct Session(String);
+
+    // login stores the name on the connection, whoami reads it back
+    struct SessionService {}
+
+    #[tonic::async_trait]
+    impl Service for SessionService {
+        fn name(&self) -> String {
+            String::from("test.Session")
+        }
+
+        async fn handle_request(
+            &self,
+            url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let ctx = RequestContext::current().unwrap();
+            let state = ctx.connection_state();
+            match url.as_str() {
+                "/test.Session/login" => {
+                    let req: HelloRequest = parse_proto(&request)?;
+                    state.insert(Session(req.name));
+                    encode_proto(&HelloReply::default())
+                }
+                "/test.Session/whoami" => match state.get::<Session>() {
+                    Some(Session(name)) => encode_proto(&HelloReply { message: name }),
+                    None => Err(tonic::Status::unauthenticated("not logged in")),
+                },
+                _ => Err(tonic::Status::unimplemented("url not found")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn session_test() {
+        let port = 12364;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.add_service(SessionService {});
+        let mut events = svr.connection_events();
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+
+        let request = HelloRequest::default();
+        let err = client
+            .request::<HelloReply>(String::from("/test.Session/whoami"), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, err.code());
+
+        let request = HelloRequest {
+            name: String::from("alice"),
+        };
+        client
+            .request::<HelloReply>(String::from("/test.Session/login"), &request, 5000)
+            .await
+            .unwrap();
+        let reply = client
+            .request::<HelloReply>(String::from("/test.Session/whoami"), &request, 5000)
+            .await
+            .unwrap();
+        assert_eq!("alice", reply.message);
+
+        let connected_id = match events.recv().await.unwrap() {
+            ConnectionEvent::Connected { connection_id, .. } => connection_id,
+            ev => panic!("unexpected event {:?}", ev),
+        };
+
+        // shutdown closes the connection with the session still attached
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+        match events.recv().await.unwrap() {
+            ConnectionEvent::Disconnected {
+                connection_id,
+                state,
+                ..
+            } => {
+                assert_eq!(connected_id, connection_id);
+                assert_eq!("alice", state.get::<Session>().unwrap().0);
+            }
+            ev => panic!("unexpected event {:?}", ev),
+        }
+    }
+}
+
+#[cfg(test)]
+mod panic_test {
+    use bytes::Bytes;
+    use tonic::Code;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        server::{encode_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    // panics on boom, replies on ping
+    struct PanicService {}
+
+    #[tonic::async_trait]
+    impl Service for PanicService {
+        fn name(&self) -> String {
+            String::from("test.Panic")
+        }
+
+        async fn handle_request(
+            &self,
+            url: String,
+            _request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            match url.as_str() {
+                "/test.Panic/boom" => panic!("boom"),
+                "/test.Panic/ping" => encode_proto(&HelloReply {
+                    message: String::from("pong"),
+                }),
+                _ => Err(tonic::Status::unimplemented("url not found")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn isolation_test() {
+        let port = 12365;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.add_service(PanicService {});
+        let panics = svr.panic_counter();
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let request = HelloRequest::default();
+
+        let err = client
+            .request::<HelloReply>(String::from("/test.Panic/boom"), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::Internal, err.code());
+        assert_eq!(1, panics.get());
+
+        // the connection and server survive the panic
+        let reply = client
+            .request::<HelloReply>(String::from("/test.Panic/ping"), &request, 5000)
+            .await
+            .unwrap();
+        assert_eq!("pong", reply.message);
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tower_test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tonic::Code;
+    use tower::{ServiceBuilder, ServiceExt};
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::{unary, Client2},
+        middleware::{FabricRequest, ServiceChannel},
+        server::Server,
+    };
+
+    use super::{
+        shutdown_test::SlowService,
+        test_grpc::hello_world::{HelloReply, HelloRequest},
+    };
+
+    const SLOW_URL: &str = "/test.Slow/slow";
+
+    #[tokio::test]
+    async fn layer_test() {
+        let port = 12366;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        // one call at a time, the rest are shed
+        svr.layer(ServiceBuilder::new().load_shed().concurrency_limit(1));
+        svr.add_service(SlowService {
+            delay: Duration::from_millis(500),
+        });
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let request = HelloRequest::default();
+
+        // Client2 as a plain tower service
+        let first = client.clone().oneshot(FabricRequest::new(
+            String::from(SLOW_URL),
+            Bytes::new(),
+            5000,
+        ));
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client
+                .request::<HelloReply>(String::from(SLOW_URL), &request, 5000)
+                .await
+        };
+        let (first, second) = tokio::join!(first, second);
+        first.unwrap();
+        assert_eq!(Code::ResourceExhausted, second.unwrap_err().code());
+
+        // client side layers through a channel
+        let channel = ServiceChannel::new(
+            ServiceBuilder::new()
+                .timeout(Duration::from_millis(100))
+                .service(client.clone()),
+        );
+        let err = unary::<_, HelloReply>(&channel, String::from(SLOW_URL), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::DeadlineExceeded, err.code());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    // layers keep their state across requests
+    #[tokio::test]
+    async fn rate_limit_test() {
+        let port = 12379;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        // one call per minute, the rest are shed
+        svr.layer(
+            ServiceBuilder::new()
+                .load_shed()
+                .rate_limit(1, Duration::from_secs(60)),
+        );
+        svr.add_service(SlowService {
+            delay: Duration::ZERO,
+        });
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let request = HelloRequest::default();
+        client
+            .request::<HelloReply>(String::from(SLOW_URL), &request, 5000)
+            .await
+            .unwrap();
+        let err = client
+            .request::<HelloReply>(String::from(SLOW_URL), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod trace_test {
+    use bytes::Bytes;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::{unary, Client2, InterceptedChannel},
+        context::RequestContext,
+        fabricrpc_header::RequestHeader,
+        server::{encode_proto, Server, Service},
+        trace::TraceContext,
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const TRACE_URL: &str = "/test.Trace/trace";
+
+    // replies with the traceparent of the server span
+    struct TraceService {}
+
+    #[tonic::async_trait]
+    impl Service for TraceService {
+        fn name(&self) -> String {
+            String::from("test.Trace")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            _request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let trace = RequestContext::current().unwrap().trace_context().unwrap();
+            encode_proto(&HelloReply {
+                message: trace.to_string(),
+            })
+        }
+    }
+
+    fn server_trace(reply: HelloReply) -> TraceContext {
+        TraceContext::parse(&reply.message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn propagation_test() {
+        let port = 12367;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let mut svr = Server::default();
+            svr.add_service(TraceService {});
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let request = HelloRequest::default();
+
+        // each plain call starts its own trace
+        let first = server_trace(
+            client
+                .request::<HelloReply>(String::from(TRACE_URL), &request, 5000)
+                .await
+                .unwrap(),
+        );
+        let second = server_trace(
+            client
+                .request::<HelloReply>(String::from(TRACE_URL), &request, 5000)
+                .await
+                .unwrap(),
+        );
+        assert_ne!(first.trace_id(), second.trace_id());
+
+        // traceparent set by the caller is continued
+        let parent = TraceContext::new_root();
+        let channel = InterceptedChannel::new(client.clone(), move |mut header: RequestHeader| {
+            parent.inject(&mut header.metadata);
+            Ok(header)
+        });
+        let reply = unary::<_, HelloReply>(&channel, String::from(TRACE_URL), &request, 5000)
+            .await
+            .unwrap();
+        let trace = server_trace(reply);
+        assert_eq!(parent.trace_id(), trace.trace_id());
+        assert_ne!(parent.span_id(), trace.span_id());
+
+        // calls made from a handler join the handler's trace
+        let mut ctx = RequestContext::default();
+        ctx.set_trace_context(parent);
+        let reply = ctx
+            .scope(client.request::<HelloReply>(String::from(TRACE_URL), &request, 5000))
+            .await
+            .unwrap();
+        assert_eq!(parent.trace_id(), server_trace(reply).trace_id());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tonic::Code;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        metrics::{MemoryRecorder, Side},
+        server::{encode_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const OK_URL: &str = "/test.Metrics/ok";
+    const FAIL_URL: &str = "/test.Metrics/fail";
+    const EVENT_URL: &str = "/test.Metrics/event";
+
+    struct MetricsService {}
+
+    #[tonic::async_trait]
+    impl Service for MetricsService {
+        fn name(&self) -> String {
+            String::from("test.Metrics")
+        }
+
+        async fn handle_request(
+            &self,
+            url: String,
+            _request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            match url.as_str() {
+                OK_URL => encode_proto(&HelloReply {
+                    message: String::from("abc"),
+                }),
+                _ => Err(tonic::Status::not_found("no such thing")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn recorder_test() {
+        let port = 12368;
+        let server_metrics = Arc::new(MemoryRecorder::default());
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.metrics(server_metrics.clone());
+        svr.add_service(MetricsService {});
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let client_metrics = Arc::new(MemoryRecorder::default());
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress)
+            .await
+            .unwrap()
+            .with_metrics(client_metrics.clone());
+        let request = HelloRequest {
+            name: String::from("bob"),
+        };
+        let request_size = prost::Message::encoded_len(&request);
+        let reply_size = prost::Message::encoded_len(&HelloReply {
+            message: String::from("abc"),
+        });
+
+        for _ in 0..2 {
+            client
+                .request::<HelloReply>(String::from(OK_URL), &request, 5000)
+                .await
+                .unwrap();
+        }
+        let err = client
+            .request::<HelloReply>(String::from(FAIL_URL), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+
+        // the server records before it replies, so both sides agree here
+        for (metrics, side) in [
+            (&client_metrics, Side::Client),
+            (&server_metrics, Side::Server),
+        ] {
+            let ok = metrics.method(side, OK_URL);
+            assert_eq!(2, ok.requests);
+            assert_eq!(0, ok.in_flight);
+            assert_eq!(Some(&2), ok.codes.get(&Code::Ok));
+            assert_eq!(0, ok.errors());
+            assert_eq!(2, ok.latencies.len());
+            assert_eq!(vec![request_size; 2], ok.request_sizes);
+            assert_eq!(vec![reply_size; 2], ok.reply_sizes);
+
+            let fail = metrics.method(side, FAIL_URL);
+            assert_eq!(1, fail.requests);
+            assert_eq!(0, fail.in_flight);
+            assert_eq!(Some(&1), fail.codes.get(&Code::NotFound));
+            assert_eq!(1, fail.errors());
+            assert_eq!(vec![0], fail.reply_sizes);
+        }
+        assert_eq!(1, server_metrics.open_connections());
+
+        // one-way msgs finish once sent
+        client
+            .send_one_way(String::from(EVENT_URL), &request)
+            .await
+            .unwrap();
+        let event = client_metrics.method(Side::Client, EVENT_URL);
+        assert_eq!(1, event.requests);
+        assert_eq!(0, event.in_flight);
+        assert_eq!(Some(&1), event.codes.get(&Code::Ok));
+        assert_eq!(vec![request_size], event.request_sizes);
+        assert_eq!(vec![0], event.reply_sizes);
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod compression_test {
+    use bytes::Bytes;
+    use tonic::Code;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        compression::{CompressionConfig, Encoding},
+        server::{encode_proto, parse_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const ECHO_URL: &str = "/test.Compression/echo";
+
+    struct EchoService {}
+
+    #[tonic::async_trait]
+    impl Service for EchoService {
+        fn name(&self) -> String {
+            String::from("test.Compression")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let req: HelloRequest = parse_proto(&request)?;
+            encode_proto(&HelloReply { message: req.name })
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiate_test() {
+        let port = 12369;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.compression(CompressionConfig {
+            send: Some(Encoding::Gzip),
+            accept: vec![Encoding::Gzip, Encoding::Zstd],
+            min_size: 64,
+        });
+        svr.add_service(EchoService {});
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        // larger than the transport max message size, only fits compressed
+        let request = HelloRequest {
+            name: "a".repeat(8192),
+        };
+
+        let zstd = client
+            .clone()
+            .with_compression(CompressionConfig::sending(Encoding::Zstd));
+        let reply: HelloReply = zstd
+            .request(String::from(ECHO_URL), &request, 5000)
+            .await
+            .unwrap();
+        assert_eq!(request.name, reply.message);
+
+        // server does not accept deflate
+        let deflate = client
+            .clone()
+            .with_compression(CompressionConfig::sending(Encoding::Deflate));
+        let err = deflate
+            .request::<HelloReply>(String::from(ECHO_URL), &request, 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::Unimplemented, err.code());
+
+        // small bodies are sent as is both ways
+        let small = HelloRequest {
+            name: String::from("bob"),
+        };
+        let reply: HelloReply = client
+            .request(String::from(ECHO_URL), &small, 5000)
+            .await
+            .unwrap();
+        assert_eq!(small.name, reply.message);
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod codec_test {
+    use bytes::Bytes;
+    use serde::{Deserialize, Serialize};
+    use tonic::Code;
+    use windows::core::HSTRING;
+
+    use crate::{
+        client::Client2,
+        codec::{self, BincodeCodec, JsonCodec, MessageCodec},
+        server::{Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const ADD_URL: &str = "/test.Calc/add";
+
+    #[derive(Serialize, Deserialize)]
+    struct AddRequest {
+        a: i64,
+        b: i64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct AddReply {
+        sum: i64,
+    }
+
+    // plain rust types, no protos
+    struct CalcService {}
+
+    #[tonic::async_trait]
+    impl Service for CalcService {
+        fn name(&self) -> String {
+            String::from("test.Calc")
+        }
+
+        fn content_type(&self) -> &'static str {
+            codec::BINCODE
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let req: AddRequest = BincodeCodec.decode(&request)?;
+            BincodeCodec.encode(&AddReply { sum: req.a + req.b })
+        }
+    }
+
+    #[tokio::test]
+    async fn content_type_test() {
+        let port = 12370;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.add_service(CalcService {});
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        let reply: AddReply = client
+            .request_with_codec(
+                String::from(ADD_URL),
+                &AddRequest { a: 2, b: 3 },
+                5000,
+                &BincodeCodec,
+            )
+            .await
+            .unwrap();
+        assert_eq!(AddReply { sum: 5 }, reply);
+
+        // same service and url, wrong codec
+        let err = client
+            .request_with_codec::<_, _, AddReply>(
+                String::from(ADD_URL),
+                &AddRequest { a: 2, b: 3 },
+                5000,
+                &JsonCodec,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(Code::Unimplemented, err.code());
+        let err = client
+            .request::<HelloReply>(String::from(ADD_URL), &HelloRequest::default(), 5000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::Unimplemented, err.code());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod vectored_test {
+    use bytes::Bytes;
+    use fabric_base::{
+        FabricCommon::FabricTransport::FABRIC_TRANSPORT_SETTINGS, FABRIC_SECURITY_CREDENTIALS,
+        FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+    };
+    use windows::core::HSTRING;
+
+    use crate::{
+        client_tr::ClientTransport,
+        fabricrpc_header::{ReplyHeader, RequestHeader},
+        server::{encode_proto, parse_proto, Server, Service},
+        sys::{Message, MessageViewer},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const ECHO_URL: &str = "/test.Vectored/echo";
+
+    struct EchoService {}
+
+    #[tonic::async_trait]
+    impl Service for EchoService {
+        fn name(&self) -> String {
+            String::from("test.Vectored")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let req: HelloRequest = parse_proto(&request)?;
+            encode_proto(&HelloReply { message: req.name })
+        }
+    }
+
+    // body split in several buffers like the C++ fabric-rpc sends it
+    #[tokio::test]
+    async fn multi_buffer_test() {
+        let port = 12371;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.add_service(EchoService {});
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let creds = FABRIC_SECURITY_CREDENTIALS {
+            Kind: FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+            Value: std::ptr::null_mut(),
+        };
+        let settings = FABRIC_TRANSPORT_SETTINGS {
+            OperationTimeoutInSeconds: 10,
+            KeepAliveTimeoutInSeconds: 10,
+            MaxMessageSize: 1024,
+            MaxConcurrentCalls: 10,
+            MaxQueueSize: 10,
+            SecurityCredentials: &creds,
+            Reserved: std::ptr::null_mut(),
+        };
+        let addr = HSTRING::from(format!("localhost:{}+/", port));
+        let mut tr = ClientTransport::new(&settings, &addr).unwrap();
+        tr.open(5000).await.unwrap();
+        tr.connect().await;
+
+        let header = encode_proto(&RequestHeader {
+            url: String::from(ECHO_URL),
+            ..Default::default()
+        })
+        .unwrap();
+        let body = encode_proto(&HelloRequest {
+            name: String::from("scattered body"),
+        })
+        .unwrap();
+        let parts = vec![body.slice(..4), body.slice(4..9), body.slice(9..)];
+        let msg = Message::create_vectored(header, parts);
+        let reply = tr.request(5000, &msg).await.unwrap();
+
+        let (header, body) = MessageViewer::new(reply).into_parts();
+        let header: ReplyHeader = parse_proto(&header).unwrap();
+        assert_eq!(tonic::Code::Ok as i32, header.status_code);
+        let reply: HelloReply = prost::Message::decode(body).unwrap();
+        assert_eq!("scattered body", reply.message);
+
+        tr.close(5000).await.unwrap();
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod chunk_test {
+    use bytes::Bytes;
+    use tonic::Code;
+    use windows::core::HSTRING;
+
+    use crate::{
+        chunk::ChunkConfig,
+        client::{unary, Client2, InterceptedChannel},
+        fabricrpc_header::RequestHeader,
+        server::{encode_proto, parse_proto, Server, Service},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const ECHO_URL: &str = "/test.Chunk/echo";
+
+    struct EchoService {}
+
+    #[tonic::async_trait]
+    impl Service for EchoService {
+        fn name(&self) -> String {
+            String::from("test.Chunk")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let req: HelloRequest = parse_proto(&request)?;
+            encode_proto(&HelloReply { message: req.name })
+        }
+    }
+
+    #[tokio::test]
+    async fn large_body_test() {
+        let port = 12372;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.chunking(ChunkConfig {
+            max_size: 64 * 1024,
+            ..Default::default()
+        });
+        svr.add_service(EchoService {});
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+
+        // many times the transport max msg size, both ways
+        let request = HelloRequest {
+            name: (0..32 * 1024)
+                .map(|i| (b'a' + (i % 26) as u8) as char)
+                .collect(),
+        };
+        let reply: HelloReply = client
+            .request(String::from(ECHO_URL), &request, 20000)
+            .await
+            .unwrap();
+        assert_eq!(request.name, reply.message);
+
+        // over the server limit
+        let too_large = HelloRequest {
+            name: "a".repeat(100 * 1024),
+        };
+        let err = client
+            .request::<HelloReply>(String::from(ECHO_URL), &too_large, 20000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+
+        // reply over the client limit
+        let small_limit = client.clone().with_chunking(ChunkConfig {
+            max_size: 4096,
+            ..Default::default()
+        });
+        let err = small_limit
+            .request::<HelloReply>(String::from(ECHO_URL), &request, 20000)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+
+        // frames get smaller to leave room for a large header
+        let with_metadata = |size: usize| {
+            InterceptedChannel::new(client.clone(), move |mut header: RequestHeader| {
+                header
+                    .metadata
+                    .insert(String::from("padding"), "p".repeat(size));
+                Ok(header)
+            })
+        };
+        let reply =
+            unary::<_, HelloReply>(&with_metadata(700), String::from(ECHO_URL), &request, 20000)
+                .await
+                .unwrap();
+        assert_eq!(request.name, reply.message);
+        // no room left for the body
+        let err =
+            unary::<_, HelloReply>(&with_metadata(2000), String::from(ECHO_URL), &request, 5000)
+                .await
+                .unwrap_err();
+        assert_eq!(Code::ResourceExhausted, err.code());
+
+        // the connection still works
+        let small = HelloRequest {
+            name: String::from("bob"),
+        };
+        let reply: HelloReply = client
+            .request(String::from(ECHO_URL), &small, 5000)
+            .await
+            .unwrap();
+        assert_eq!(small.name, reply.message);
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod protocol_test {
+    use bytes::Bytes;
+    use fabric_base::{
+        FabricCommon::FabricTransport::{
+            FABRIC_TRANSPORT_LISTEN_ADDRESS, FABRIC_TRANSPORT_SETTINGS,
+        },
+        FABRIC_SECURITY_CREDENTIALS, FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+    };
+    use serde::{Deserialize, Serialize};
+    use tonic::Code;
+    use windows::core::{HSTRING, PCWSTR};
+
+    use crate::{
+        client::Client2,
+        client_tr::ClientTransport,
+        codec::JsonCodec,
+        compression::{CompressionConfig, Encoding},
+        fabricrpc_header::{ReplyHeader, RequestHeader},
+        protocol::{self, PeerInfo},
+        server::{encode_proto, parse_proto, Server, Service},
+        server_tr::ServerTransport,
+        sys::{Message, MessageViewer},
+    };
+
+    use super::test_grpc::hello_world::{HelloReply, HelloRequest};
+
+    const ECHO_URL: &str = "/test.Protocol/echo";
+
+    struct EchoService {}
+
+    #[tonic::async_trait]
+    impl Service for EchoService {
+        fn name(&self) -> String {
+            String::from("test.Protocol")
+        }
+
+        async fn handle_request(
+            &self,
+            _url: String,
+            request: Bytes,
+        ) -> std::result::Result<Bytes, tonic::Status> {
+            let req: HelloRequest = parse_proto(&request)?;
+            encode_proto(&HelloReply { message: req.name })
+        }
+    }
+
+    fn settings(creds: &FABRIC_SECURITY_CREDENTIALS) -> FABRIC_TRANSPORT_SETTINGS {
+        FABRIC_TRANSPORT_SETTINGS {
+            OperationTimeoutInSeconds: 10,
+            KeepAliveTimeoutInSeconds: 10,
+            MaxMessageSize: 1024,
+            MaxConcurrentCalls: 10,
+            MaxQueueSize: 10,
+            SecurityCredentials: creds,
+            Reserved: std::ptr::null_mut(),
+        }
+    }
+
+    async fn raw_request(
+        tr: &ClientTransport,
+        header: &RequestHeader,
+        body: Bytes,
+    ) -> (ReplyHeader, Bytes) {
+        let msg = Message::create(encode_proto(header).unwrap(), body);
+        let reply = tr.request(5000, &msg).await.unwrap();
+        let (header, body) = MessageViewer::new(reply).into_bytes();
+        (parse_proto(&header).unwrap(), body)
+    }
+
+    // client sending the header from before versioning
+    #[tokio::test]
+    async fn old_client_test() {
+        let port = 12373;
+        let (stoptx, stoprx) = tokio::sync::oneshot::channel::<()>();
+        let mut svr = Server::default();
+        svr.compression(CompressionConfig {
+            send: Some(Encoding::Gzip),
+            min_size: 64,
+            ..Default::default()
+        });
+        svr.add_service(EchoService {});
+        let server = tokio::spawn(async move {
+            svr.serve_with_shutdown(port, async {
+                stoprx.await.ok();
+            })
+            .await
+        });
+
+        let creds = FABRIC_SECURITY_CREDENTIALS {
+            Kind: FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+            Value: std::ptr::null_mut(),
+        };
+        let addr = HSTRING::from(format!("localhost:{}+/", port));
+        let mut tr = ClientTransport::new(&settings(&creds), &addr).unwrap();
+        tr.open(5000).await.unwrap();
+        tr.connect().await;
+
+        let request = HelloRequest {
+            name: "a".repeat(512),
+        };
+        // accepts gzip but has no version, the reply is sent as is
+        let old = RequestHeader {
+            url: String::from(ECHO_URL),
+            accept_encoding: vec![String::from("gzip")],
+            ..Default::default()
+        };
+        let (header, body) = raw_request(&tr, &old, encode_proto(&request).unwrap()).await;
+        assert_eq!(Code::Ok as i32, header.status_code);
+        assert!(header.encoding.is_empty());
+        assert_eq!(protocol::PROTOCOL_VERSION, header.protocol_version);
+        let reply: HelloReply = parse_proto(&body).unwrap();
+        assert_eq!(request.name, reply.message);
+
+        // same request from a versioned client is compressed
+        let new = RequestHeader {
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities: protocol::CAPABILITIES,
+            ..old
+        };
+        let (header, _) = raw_request(&tr, &new, encode_proto(&request).unwrap()).await;
+        assert_eq!("gzip", header.encoding);
+
+        let handshake = RequestHeader {
+            url: String::from(protocol::HANDSHAKE_URL),
+            ..Default::default()
+        };
+        let (header, _) = raw_request(&tr, &handshake, Bytes::new()).await;
+        assert_eq!(Code::Ok as i32, header.status_code);
+        assert_eq!(PeerInfo::local(), PeerInfo::of_reply(&header));
+
+        tr.close(5000).await.unwrap();
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Name {
+        name: String,
+    }
+
+    // server replying like before versioning, it only knows url and the proto body
+    #[tokio::test]
+    async fn old_server_test() {
+        let port = 12374;
+        let (stoptx, mut stoprx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let creds = FABRIC_SECURITY_CREDENTIALS {
+                Kind: FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+                Value: std::ptr::null_mut(),
+            };
+            let mut serveraddr = FABRIC_TRANSPORT_LISTEN_ADDRESS::default();
+            let host = HSTRING::from("localhost");
+            let path = HSTRING::from("/");
+            serveraddr.IPAddressOrFQDN = PCWSTR(host.as_ptr());
+            serveraddr.Port = port;
+            serveraddr.Path = PCWSTR(path.as_ptr());
+            let mut listener = ServerTransport::new(&settings(&creds), &serveraddr).unwrap();
+            listener.open().await.unwrap();
+            loop {
+                let mut conn;
+                tokio::select! {
+                    _ = (&mut stoprx) => { break; },
+                    x = listener.async_accept() => {
+                        conn = x;
+                    }
+                }
+                tokio::spawn(async move {
+                    while let Some(mut req) = conn.async_accept().await {
+                        let vw = MessageViewer::new(req.get_request_msg().clone());
+                        let header: RequestHeader = parse_proto(vw.get_header()).unwrap();
+                        let mut replyheader = ReplyHeader {
+                            status_code: Code::Ok as i32,
+                            status_message: String::from("Ok"),
+                            ..Default::default()
+                        };
+                        let mut replybody = Bytes::new();
+                        let parsed = parse_proto::<HelloRequest>(&vw.get_body());
+                        match parsed {
+                            Ok(hello) if header.url == ECHO_URL => {
+                                replybody = encode_proto(&HelloReply {
+                                    message: hello.name,
+                                })
+                                .unwrap();
+                            }
+                            Ok(_) => {
+                                replyheader.status_code = Code::Unimplemented as i32;
+                                replyheader.status_message = String::from("url not found");
+                            }
+                            Err(st) => {
+                                replyheader.status_code = st.code() as i32;
+                                replyheader.status_message = String::from(st.message());
+                            }
+                        }
+                        let header_buff = encode_proto(&replyheader).unwrap();
+                        req.complete(Message::create(header_buff, replybody));
+                    }
+                });
+            }
+            listener.close().await.unwrap();
+        });
+
+        let connectionaddress = HSTRING::from(format!("localhost:{}+/", port));
+        let client = Client2::connect(connectionaddress).await.unwrap();
+        // would be gzip for a versioned server
+        let client = client.with_compression(CompressionConfig {
+            send: Some(Encoding::Gzip),
+            min_size: 0,
+            ..Default::default()
+        });
+        assert_eq!(None, client.peer());
+
+        let request = HelloRequest {
+            name: "b".repeat(300),
+        };
+        let reply: HelloReply = client
+            .request(String::from(ECHO_URL), &request, 5000)
+            .await
+            .unwrap();
+        assert_eq!(request.name, reply.message);
+        assert_eq!(Some(PeerInfo::LEGACY), client.peer());
+
+        // the old server would misread json as protobuf
+        let name = Name {
+            name: String::from("json"),
+        };
+        let err = client
+            .request_with_codec::<_, _, Name>(String::from(ECHO_URL), &name, 5000, &JsonCodec)
+            .await
+            .unwrap_err();
+        assert_eq!(Code::Unimplemented, err.code());
+
+        stoptx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
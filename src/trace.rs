@@ -0,0 +1,180 @@
+// W3C trace context carried in request header metadata,
+// so client and server spans of one call share a trace id.
+
+use std::{collections::HashMap, fmt, time::Instant};
+
+use bytes::Bytes;
+use tonic::Status;
+use tracing::{field, Span};
+
+// metadata key, same as the http header
+pub const TRACEPARENT: &str = "traceparent";
+
+const VERSION: &str = "00";
+const FLAG_SAMPLED: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    flags: u8,
+}
+
+impl TraceContext {
+    // start a new trace
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: non_zero(rand::random()),
+            span_id: non_zero(rand::random()),
+            flags: FLAG_SAMPLED,
+        }
+    }
+
+    // new span in the same trace
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            span_id: non_zero(rand::random()),
+            ..*self
+        }
+    }
+
+    // parse a traceparent value like 00-<32 hex trace id>-<16 hex span id>-<2 hex flags>
+    pub fn parse(value: &str) -> Option<TraceContext> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // later versions may append fields
+        if version.len() != 2 || version == "ff" || (version == VERSION && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        if ![version, trace_id, span_id, flags]
+            .iter()
+            .all(|s| is_hex(s))
+        {
+            return None;
+        }
+        let ctx = TraceContext {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        if ctx.trace_id == 0 || ctx.span_id == 0 {
+            return None;
+        }
+        Some(ctx)
+    }
+
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Option<TraceContext> {
+        metadata
+            .get(TRACEPARENT)
+            .and_then(|v| TraceContext::parse(v))
+    }
+
+    pub fn inject(&self, metadata: &mut HashMap<String, String>) {
+        metadata.insert(TRACEPARENT.to_string(), self.to_string());
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    pub fn span_id(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & FLAG_SAMPLED != 0
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:032x}-{:016x}-{:02x}",
+            VERSION, self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+// fill the outcome fields of a client or server call span
+pub(crate) fn record_result(span: &Span, result: &Result<Bytes, Status>, started: Instant) {
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    match result {
+        Ok(reply) => {
+            span.record("status", field::debug(tonic::Code::Ok));
+            span.record("reply_size", reply.len());
+            tracing::debug!(parent: span, "call succeeded");
+        }
+        Err(status) => {
+            span.record("status", field::debug(status.code()));
+            tracing::debug!(parent: span, error = status.message(), "call failed");
+        }
+    }
+}
+
+// lowercase only, per the spec
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// all zero ids are invalid
+fn non_zero<T: Default + PartialEq + From<u8>>(id: T) -> T {
+    if id == T::default() {
+        T::from(1)
+    } else {
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::TraceContext;
+
+    #[test]
+    fn traceparent_test() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let ctx = TraceContext::parse(value).unwrap();
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", ctx.trace_id());
+        assert_eq!("00f067aa0ba902b7", ctx.span_id());
+        assert!(ctx.is_sampled());
+        assert_eq!(value, ctx.to_string());
+
+        let child = ctx.child();
+        assert_eq!(ctx.trace_id(), child.trace_id());
+        assert_ne!(ctx.span_id(), child.span_id());
+
+        let mut metadata = HashMap::new();
+        child.inject(&mut metadata);
+        assert_eq!(Some(child), TraceContext::from_metadata(&metadata));
+
+        // future versions may have more fields
+        let ctx = TraceContext::parse(&format!("cc-{}-{}-00-extra", ctx.trace_id(), ctx.span_id()));
+        assert!(!ctx.unwrap().is_sampled());
+    }
+
+    #[test]
+    fn invalid_test() {
+        for value in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-xbf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(None, TraceContext::parse(value), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// Captures the conformance fixtures from the C++ fabric-rpc.
+// Sits between a C++ client and a C++ server, forwards every request and
+// records the msgs in tests/conformance/fixtures.txt, see the README next to it.
+//
+// usage: cargo run --features capture --bin conformance_capture -- <fabric-rpc revision> <listen port> <C++ server address> [out file]
+
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use fabric_base::{
+    FabricCommon::FabricTransport::{FABRIC_TRANSPORT_LISTEN_ADDRESS, FABRIC_TRANSPORT_SETTINGS},
+    FABRIC_SECURITY_CREDENTIALS, FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+};
+use fabric_rpc_rs::{
+    client_tr::ClientTransport,
+    fabricrpc_header::{ReplyHeader, RequestHeader},
+    protocol::{self, PeerInfo},
+    server::encode_proto,
+    server_tr::ServerTransport,
+    sys::{Message, MessageViewer},
+};
+use prost::Message as _;
+use tonic::Code;
+use windows::core::{HSTRING, PCWSTR};
+
+const TIMEOUT_MILLIS: u32 = 10000;
+// sent to the C++ server, which does not know it
+const UNKNOWN_URL: &str = "/fabricrpc.Conformance/Unknown";
+
+// msgs in the order they were seen, the first one per name is kept
+#[derive(Default)]
+struct Recorder {
+    names: HashSet<String>,
+    msgs: Vec<(String, Bytes)>,
+}
+
+impl Recorder {
+    fn record(&mut self, name: &str, msg: Bytes) {
+        if self.names.insert(name.to_string()) {
+            tracing::info!(name, "captured");
+            self.msgs.push((name.to_string(), msg));
+        }
+    }
+
+    // request and reply of a call between the C++ client and server
+    fn record_call(&mut self, request: (Bytes, Bytes), reply: (Bytes, Bytes)) {
+        let code = match ReplyHeader::decode(reply.0.clone()) {
+            Ok(h) => Code::from_i32(h.status_code),
+            Err(e) => {
+                tracing::warn!(error = %e, "skipped reply with invalid header");
+                return;
+            }
+        };
+        if code == Code::Ok {
+            if !self.names.contains("request_header") {
+                self.record("request_header", request.0);
+                self.record("request_body", request.1);
+                self.record("reply_ok_header", reply.0);
+                self.record("reply_ok_body", reply.1);
+            }
+            return;
+        }
+        self.record(&format!("reply_{}_header", code_name(code)), reply.0);
+    }
+
+    fn to_fixtures(&self, revision: &str) -> String {
+        let mut out = String::new();
+        out.push_str("# Msgs of the C++ fabric-rpc, captured by tests/conformance/capture.rs.\n");
+        out.push_str("# Regenerate as in tests/conformance/README.md, do not edit by hand.\n");
+        writeln!(out, "# source: fabric-rpc {}", revision).unwrap();
+        for (name, msg) in &self.msgs {
+            let hex: String = msg.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{} {}", name, hex).unwrap();
+        }
+        out
+    }
+}
+
+// snake case of the code, e.g. invalid_argument
+fn code_name(code: Code) -> String {
+    let mut name = String::new();
+    for (i, c) in format!("{:?}", code).chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+fn settings(creds: &FABRIC_SECURITY_CREDENTIALS) -> FABRIC_TRANSPORT_SETTINGS {
+    FABRIC_TRANSPORT_SETTINGS {
+        OperationTimeoutInSeconds: 10,
+        KeepAliveTimeoutInSeconds: 10,
+        MaxMessageSize: 1024,
+        MaxConcurrentCalls: 10,
+        MaxQueueSize: 10,
+        SecurityCredentials: creds,
+        Reserved: std::ptr::null_mut(),
+    }
+}
+
+// how the C++ server answers urls it does not serve, as a Rust client sends them
+async fn probe(tr: &ClientTransport, recorder: &Mutex<Recorder>) {
+    let local = PeerInfo::local();
+    for (name, url) in [
+        ("reply_handshake_header", protocol::HANDSHAKE_URL),
+        ("reply_unknown_url_header", UNKNOWN_URL),
+    ] {
+        let header = RequestHeader {
+            url: String::from(url),
+            protocol_version: local.version,
+            capabilities: local.capabilities,
+            ..Default::default()
+        };
+        let msg = Message::create(encode_proto(&header).unwrap(), Bytes::new());
+        let reply = tr.request(TIMEOUT_MILLIS, &msg).await.unwrap();
+        let (header, _) = MessageViewer::new(reply).into_bytes();
+        recorder.lock().unwrap().record(name, header);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        eprintln!(
+            "usage: {} <fabric-rpc revision> <listen port> <C++ server address> [out file]",
+            args[0]
+        );
+        std::process::exit(2);
+    }
+    let revision = args[1].clone();
+    let port: u32 = args[2].parse().expect("listen port");
+    let server_addr = HSTRING::from(args[3].as_str());
+    let out = args
+        .get(4)
+        .cloned()
+        .unwrap_or_else(|| String::from("tests/conformance/fixtures.txt"));
+
+    let creds = FABRIC_SECURITY_CREDENTIALS {
+        Kind: FABRIC_SECURITY_CREDENTIAL_KIND_NONE,
+        Value: std::ptr::null_mut(),
+    };
+    let mut tr = ClientTransport::new(&settings(&creds), &server_addr).unwrap();
+    tr.open(TIMEOUT_MILLIS).await.unwrap();
+    tr.connect().await;
+    let tr = Arc::new(tr);
+
+    let recorder = Arc::new(Mutex::new(Recorder::default()));
+    probe(&tr, &recorder).await;
+
+    let host = HSTRING::from("localhost");
+    let path = HSTRING::from("/");
+    let listen_addr = FABRIC_TRANSPORT_LISTEN_ADDRESS {
+        IPAddressOrFQDN: PCWSTR(host.as_ptr()),
+        Port: port,
+        Path: PCWSTR(path.as_ptr()),
+    };
+    let mut listener = ServerTransport::new(&settings(&creds), &listen_addr).unwrap();
+    let addr = listener.open().await.unwrap();
+    tracing::info!(
+        from = ?addr,
+        to = ?server_addr,
+        "forwarding, run the C++ client against it and stop with ctrl-c"
+    );
+
+    loop {
+        let mut conn;
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => { break; },
+            x = listener.async_accept() => {
+                conn = x;
+            }
+        }
+        let tr = tr.clone();
+        let recorder = recorder.clone();
+        tokio::spawn(async move {
+            while let Some(mut req) = conn.async_accept().await {
+                if req.is_one_way() {
+                    continue;
+                }
+                let request = MessageViewer::new(req.get_request_msg().clone()).into_bytes();
+                let msg = Message::create(request.0.clone(), request.1.clone());
+                let reply = match tr.request(req.timeout_milliseconds(), &msg).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        // the C++ client must not wait for its timeout
+                        tracing::warn!(error = %e, "C++ server failed the request");
+                        let header = ReplyHeader {
+                            status_code: Code::Unavailable as i32,
+                            status_message: format!("C++ server failed the request: {}", e),
+                            ..Default::default()
+                        };
+                        req.complete(Message::create(
+                            encode_proto(&header).unwrap(),
+                            Bytes::new(),
+                        ));
+                        continue;
+                    }
+                };
+                let reply = MessageViewer::new(reply).into_bytes();
+                req.complete(Message::create(reply.0.clone(), reply.1.clone()));
+                recorder.lock().unwrap().record_call(request, reply);
+            }
+        });
+    }
+    listener.close().await.unwrap();
+    tr.close(TIMEOUT_MILLIS).await.unwrap();
+
+    let fixtures = recorder.lock().unwrap().to_fixtures(&revision);
+    std::fs::write(&out, fixtures).unwrap();
+    tracing::info!(file = %out, "wrote fixtures");
+}
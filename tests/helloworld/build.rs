@@ -3,6 +3,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // generate fabric-rpc example code
     fabric_rpc_build::compile_protos("../../proto/fabrichello.proto")?;
 
-    fabric_rpc_build::compile_protos("../../proto/todolist.proto")?;
+    // todolist also gets a mock client for unit tests
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    fabric_rpc_build::configure()
+        .build_mock(true)
+        .file_descriptor_set_path(out_dir.join("todolist_descriptor.bin"))
+        .compile(&["../../proto/todolist.proto"], &["../../proto"])?;
+
+    // Record and Flush are one-way, Remove returns Empty but stays unary
+    fabric_rpc_build::configure()
+        .one_way_method("eventlog.EventLog.Record")
+        .one_way_method("eventlog.EventLog.Flush")
+        .compile(&["../../proto/eventlog.proto"], &["../../proto"])?;
+
+    // generate tonic and fabric-rpc code sharing the tonic service trait
+    fabric_rpc_build::configure()
+        .tonic_compat(true)
+        .compile(&["../../proto/helloworld.proto"], &["../../proto"])?;
     Ok(())
 }